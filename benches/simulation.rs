@@ -0,0 +1,139 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use rusty_snake::bot::calculate_direction;
+use rusty_snake::game::{DIRECTION, Field, FoodKind, Game, Snake};
+use rusty_snake::protocol::{decode, encode, UdpFrame, COMMANDS};
+
+static BOARD_HEIGHT: i32 = 100;
+static BOARD_WIDTH: i32 = 200;
+
+fn draw_block(_y: &i32, _x: &i32, _color: &i16) {}
+fn draw_food(_y: &i32, _x: &i32, _kind: &FoodKind) {}
+fn clear_field(_y: &i32, _x: &i32) {}
+
+fn headless_game() -> Game {
+    let mut game = Game::new(BOARD_HEIGHT, BOARD_WIDTH, draw_food, draw_block, clear_field);
+    game.set_start(0, 0);
+    game
+}
+
+/* Snake of the given length laid out in a straight line to the left of its head. */
+fn long_snake(y: i32, length: i32) -> Snake {
+    let body = (0..length).rev().map(|x| Field::new(y, x)).collect();
+    Snake::with_body(body, DIRECTION::Right, draw_block, 0)
+}
+
+/* Snake winding through the whole board, leaving only `free` fields empty. */
+fn board_filling_snake(free: usize) -> Snake {
+    let mut fields = vec![];
+    for y in 1..BOARD_HEIGHT - 1 {
+        for x in 1..BOARD_WIDTH - 1 {
+            let x = if y % 2 == 0 { BOARD_WIDTH - 1 - x } else { x };
+            fields.push(Field::new(y, x));
+        }
+    }
+    fields.truncate(fields.len() - free);
+
+    Snake::with_body(fields.into_iter().rev().collect(), DIRECTION::Right, draw_block, 0)
+}
+
+/* What a single tick cost when the body was a `Vec` with the head at index 0. */
+fn move_vec_body(body: &mut Vec<Field>) {
+    let new_head = Field::new(body[0].y(), body[0].x() + 1);
+    body.insert(0, new_head);
+    body.pop();
+}
+
+fn move_snake(c: &mut Criterion) {
+    let mut group = c.benchmark_group("move_snake");
+    for length in [100, 1_000, 10_000].iter() {
+        group.bench_with_input(BenchmarkId::new("vec_deque", length), length, |b, &length| {
+            let mut snake = long_snake(0, length);
+            b.iter(|| {
+                snake.move_snake();
+                black_box(snake.remove_tail());
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("vec", length), length, |b, &length| {
+            let mut body: Vec<Field> = long_snake(0, length).body().iter().cloned().collect();
+            b.iter(|| {
+                move_vec_body(&mut body);
+                black_box(&body);
+            })
+        });
+    }
+    group.finish();
+}
+
+fn check_collisions(c: &mut Criterion) {
+    let mut group = c.benchmark_group("check_collisions");
+    for length in [10, 100, BOARD_WIDTH - 2].iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(length), length, |b, &length| {
+            let mut game = headless_game();
+            let snakes = vec![long_snake(10, length), long_snake(20, length)];
+            game.init_grid(&snakes);
+            b.iter(|| black_box(game.check_collisions(&snakes)))
+        });
+    }
+    group.finish();
+}
+
+fn new_food(c: &mut Criterion) {
+    let mut group = c.benchmark_group("new_food");
+    for free in [1, 10, 1_000].iter() {
+        group.bench_with_input(BenchmarkId::new("free_fields", free), free, |b, &free| {
+            let mut game = headless_game();
+            game.init_grid(&[board_filling_snake(free)]);
+            b.iter(|| black_box(game.new_food()))
+        });
+    }
+    group.finish();
+}
+
+fn bot_decision(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bot_decision");
+    for food_count in [1, 3, 10].iter() {
+        group.bench_with_input(BenchmarkId::new("food", food_count), food_count, |b, &food_count| {
+            let mut game = headless_game();
+            let snakes = vec![long_snake(10, 50), long_snake(20, 50)];
+            game.init_grid(&snakes);
+            game.set_food_count(food_count);
+            game.init_food();
+            b.iter(|| {
+                let (head_y, head_x) = snakes[1].get_head();
+                let (target_y, target_x) = game.get_nearest_food(&head_y, &head_x);
+                black_box(calculate_direction(&snakes[1], &target_y, &target_x, &head_y, &head_x))
+            })
+        });
+    }
+    group.finish();
+}
+
+fn frame_serialization(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_serialization");
+    for length in [10, 100, 1_000].iter() {
+        let mut game = headless_game();
+        let snakes = vec![long_snake(10, *length), long_snake(20, *length)];
+        game.init_grid(&snakes);
+        game.set_food_count(3);
+        game.init_food();
+        let frame = COMMANDS::ServerData(UdpFrame{tick: 0,
+                                                  snake1: snakes[0].body().clone(),
+                                                  snake2: snakes[1].body().clone(),
+                                                  foods: game.get_foods_win(),
+                                                  inputs: vec![0, 0],
+                                                  refresh_timeout: game.get_refresh_timeout()});
+        let encoded = encode(&frame);
+
+        group.bench_with_input(BenchmarkId::new("encode", length), &frame, |b, frame| {
+            b.iter(|| black_box(encode(frame)))
+        });
+        group.bench_with_input(BenchmarkId::new("decode", length), &encoded, |b, encoded| {
+            b.iter(|| black_box(decode(encoded).is_ok()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, move_snake, check_collisions, new_food, bot_decision, frame_serialization);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use rusty_snake::protocol::{decode, encode};
+
+fuzz_target!(|data: &[u8]| {
+    // Anything that decodes has to survive a round trip unchanged, payload and all.
+    if let Ok(command) = decode(data) {
+        let encoded = encode(&command);
+        let decoded = decode(&encoded).expect("an encoded command has to decode");
+        assert_eq!(encode(&decoded), encoded);
+    }
+});
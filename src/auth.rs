@@ -0,0 +1,227 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::protocol::{decode, encode, packet_kind, SignedPacket, COMMANDS, MAX_DATAGRAM};
+use crate::transport::Transport;
+
+type HmacSha256 = Hmac<Sha256>;
+
+static SALT: &[u8] = b"rusty_snake";
+/* Slows down guessing the password from a captured datagram. */
+static ROUNDS: u32 = 10_000;
+/* Seconds a challenge stays the same, its answer is good for one more period after that. */
+static CHALLENGE_PERIOD: u64 = 30;
+/* A server forgets an address that has been silent for this long. */
+static SESSION_EXPIRY: Duration = Duration::from_secs(300);
+/* Only datagrams signed this many seconds either side of now are taken, so a recorded
+ * one can't be played back later, in the session it was recorded in or outside of it. */
+static FRESHNESS: u64 = 30;
+/* An address gets at most one challenge this often. */
+static CHALLENGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/* Secret both sides derive from the password, datagrams are signed with it. */
+#[derive(Clone)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    pub fn from_password(password: &str) -> Self {
+        let mut key = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), SALT, ROUNDS, &mut key);
+        Key(key)
+    }
+
+    /* Every answered challenge starts a session with a key of its own, so datagrams
+     * recorded in one session are no use in another. */
+    fn for_session(&self, challenge: u64) -> Self {
+        let mut mac = self.mac();
+        mac.update(b"session");
+        mac.update(&challenge.to_le_bytes());
+        Key(mac.finalize().into_bytes().into())
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.0).unwrap()
+    }
+
+    pub fn sign(&self, packet: &[u8]) -> Vec<u8> {
+        let time = now();
+        let mut mac = self.mac();
+        mac.update(&time.to_le_bytes());
+        mac.update(packet);
+        let tag = mac.finalize().into_bytes()[..16].try_into().unwrap();
+        encode(&COMMANDS::Signed(SignedPacket{packet: packet.to_vec(), time, tag}))
+    }
+
+    pub fn verify(&self, signed: &SignedPacket) -> bool {
+        let mut mac = self.mac();
+        mac.update(&signed.time.to_le_bytes());
+        mac.update(&signed.packet);
+        mac.verify_truncated_left(&signed.tag).is_ok()
+    }
+
+    /* What an address has to send back signed before the server listens to it. */
+    fn challenge(&self, address: SocketAddr, period: u64) -> u64 {
+        let mut mac = self.mac();
+        mac.update(b"challenge");
+        mac.update(address.to_string().as_bytes());
+        mac.update(&period.to_le_bytes());
+        u64::from_le_bytes(mac.finalize().into_bytes()[..8].try_into().unwrap())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn current_period() -> u64 {
+    now() / CHALLENGE_PERIOD
+}
+
+/* Signed recently, so an old recording of it is no use. */
+fn is_fresh(signed: &SignedPacket) -> bool {
+    signed.time.abs_diff(now()) <= FRESHNESS
+}
+
+/* Only a request to join starts a session, nothing else makes a server send a challenge. */
+fn is_join(packet: &[u8]) -> bool {
+    matches!(decode(packet), Ok(COMMANDS::Connect{..}) | Ok(COMMANDS::Reconnect{..}))
+}
+
+struct Session {
+    challenge: u64,
+    key: Key,
+    heard: Instant,
+    /* The other side has signed something with the session's key, so it has the session too.
+     * Until then a client's requests to join also go out signed with the password's key:
+     * its answer to the challenge may have been lost, and only those get the server to ask again. */
+    confirmed: bool,
+}
+
+/* Signs everything sent over another transport and drops whatever comes in unsigned, forged or stale.
+ *
+ * A server only listens to an address once it has answered a challenge signed with
+ * the password's key. The answer starts a session on both sides, from then on the
+ * datagrams between them are signed with the session's key. A request to join signed
+ * with the password's key is challenged again, whether the server has a session for
+ * the address or not, so a lost answer doesn't keep the client out. */
+pub struct Authenticated {
+    inner: Box<dyn Transport>,
+    key: Key,
+    server: bool,
+    sessions: RefCell<HashMap<SocketAddr, Session>>,
+    /* When each address was last sent a challenge. */
+    challenged: RefCell<HashMap<SocketAddr, Instant>>,
+}
+
+impl Authenticated {
+    pub fn server(inner: Box<dyn Transport>, key: Key) -> Self {
+        Authenticated { inner, key, server: true, sessions: RefCell::default(), challenged: RefCell::default() }
+    }
+
+    pub fn client(inner: Box<dyn Transport>, key: Key) -> Self {
+        Authenticated { inner, key, server: false, sessions: RefCell::default(), challenged: RefCell::default() }
+    }
+
+    /* Challenges are always signed with the password's key, there is no session yet.
+     * A source address can be forged, so none of them gets more than one now and then. */
+    fn send_challenge(&self, challenge: u64, address: SocketAddr) {
+        let mut challenged = self.challenged.borrow_mut();
+        if challenged.get(&address).is_some_and(|sent| sent.elapsed() < CHALLENGE_INTERVAL) {
+            return;
+        }
+        challenged.retain(|_, sent| sent.elapsed() < CHALLENGE_INTERVAL);
+        challenged.insert(address, Instant::now());
+        let _ = self.inner.send_to(&self.key.sign(&encode(&COMMANDS::Challenge(challenge))), address);
+    }
+
+    /* A client answers every challenge, a server checks the answer was its own for that address. */
+    fn challenged(&self, challenge: u64, source: SocketAddr) {
+        if self.server {
+            let period = current_period();
+            let answered = [period, period - 1].iter().any(|period| self.key.challenge(source, *period) == challenge);
+            if !answered {
+                return;
+            }
+        } else {
+            self.send_challenge(challenge, source);
+        }
+        let mut sessions = self.sessions.borrow_mut();
+        sessions.retain(|_, session| session.heard.elapsed() < SESSION_EXPIRY);
+        // The same challenge again, the session goes on as it was.
+        if let Some(session) = sessions.get_mut(&source).filter(|session| session.challenge == challenge) {
+            session.heard = Instant::now();
+            return;
+        }
+        // A server only ever gets the answer to its own challenge, the client has the session already.
+        let session = Session{challenge, key: self.key.for_session(challenge), heard: Instant::now(), confirmed: self.server};
+        sessions.insert(source, session);
+    }
+}
+
+impl Transport for Authenticated {
+    fn send_to(&self, packet: &[u8], address: SocketAddr) -> io::Result<usize> {
+        let signed = match self.sessions.borrow().get(&address) {
+            Some(session) => {
+                if !session.confirmed && is_join(packet) {
+                    self.inner.send_to(&self.key.sign(packet), address)?;
+                }
+                session.key.sign(packet)
+            },
+            None => self.key.sign(packet),
+        };
+        self.inner.send_to(&signed, address).map(|_| packet.len())
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut datagram = [0; MAX_DATAGRAM];
+        loop {
+            let (length, source) = self.inner.recv_from(&mut datagram)?;
+            let signed = match decode(&datagram[..length]) {
+                Ok(COMMANDS::Signed(signed)) => signed,
+                _ => continue,
+            };
+
+            if packet_kind(&signed.packet) == Some(COMMANDS::Challenge(0).kind()) {
+                if let (true, Ok(COMMANDS::Challenge(challenge))) = (self.key.verify(&signed) && is_fresh(&signed), decode(&signed.packet)) {
+                    self.challenged(challenge, source);
+                }
+                continue;
+            }
+            if !is_fresh(&signed) {
+                continue;
+            }
+            match self.sessions.borrow_mut().get_mut(&source) {
+                Some(session) if session.key.verify(&signed) => {
+                    session.heard = Instant::now();
+                    session.confirmed = true;
+                },
+                // The server doesn't know this address yet, forgot it, or the client never got to know
+                // the server has it, so it asks for proof. Only a request to join is answered, a replayed
+                // datagram can't aim challenges at others.
+                _ if self.server && self.key.verify(&signed) && is_join(&signed.packet) => {
+                    self.send_challenge(self.key.challenge(source, current_period()), source);
+                    continue;
+                },
+                // Forged, or signed in a session the other side no longer has.
+                _ => continue,
+            }
+
+            if signed.packet.len() > buffer.len() {
+                continue;
+            }
+            buffer[..signed.packet.len()].copy_from_slice(&signed.packet);
+            return Ok((signed.packet.len(), source));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
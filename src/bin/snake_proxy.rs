@@ -0,0 +1,106 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use clap::{App, Arg};
+
+use rusty_snake::network::listen_address;
+use rusty_snake::proxy::{Impairment, Proxy};
+
+fn main() {
+    let matches = App::new("Rusty Snake proxy")
+        .version("0.1.0")
+        .about("Forwards Rusty Snake traffic to a server over a simulated bad network.")
+        .arg(Arg::with_name("server")
+            .short("c")
+            .long("conn")
+            .takes_value(true)
+            .required(true)
+            .validator(is_server_address)
+            .help("Address of the Snake server to forward to"))
+
+        .arg(Arg::with_name("port")
+            .short("p")
+            .long("port")
+            .takes_value(true)
+            .required(true)
+            .validator(|value| match value.parse::<u16>() {
+                Ok(_) => Ok(()),
+                Err(_) => Err(format!("'{}' is not a valid port number", value)),
+            })
+            .help("Port the clients connect to instead of the server"))
+
+        .arg(Arg::with_name("loss")
+            .long("loss")
+            .takes_value(true)
+            .validator(is_percentage)
+            .help("Percentage of datagrams lost (default: 0)"))
+
+        .arg(Arg::with_name("latency")
+            .long("latency")
+            .takes_value(true)
+            .validator(is_millis)
+            .help("Milliseconds every datagram is delayed, each way (default: 0)"))
+
+        .arg(Arg::with_name("jitter")
+            .long("jitter")
+            .takes_value(true)
+            .validator(is_millis)
+            .help("Milliseconds of random delay on top of the latency (default: 0)"))
+
+        .arg(Arg::with_name("duplicate")
+            .long("duplicate")
+            .takes_value(true)
+            .validator(is_percentage)
+            .help("Percentage of datagrams delivered twice (default: 0)"))
+
+        .arg(Arg::with_name("reorder")
+            .long("reorder")
+            .takes_value(true)
+            .validator(is_percentage)
+            .help("Percentage of datagrams held back behind later ones (default: 0)"))
+
+        .get_matches();
+
+    let percent = |name: &str| matches.value_of(name)
+        .map(|value| value.parse::<f64>().expect("Unable to parse percentage"))
+        .unwrap_or(0.0) / 100.0;
+    let millis = |name: &str| matches.value_of(name)
+        .map(|value| Duration::from_millis(value.parse::<u64>().expect("Unable to parse milliseconds")))
+        .unwrap_or_default();
+    let impairment = Impairment {
+        loss: percent("loss"),
+        latency: millis("latency"),
+        jitter: millis("jitter"),
+        duplicate: percent("duplicate"),
+        reorder: percent("reorder"),
+    };
+
+    let server: SocketAddr = matches.value_of("server").unwrap().to_socket_addrs().ok()
+        .and_then(|mut addresses| addresses.next())
+        .expect("Unable to parse socket address");
+    let proxy = Proxy::bind(listen_address(None, matches.value_of("port").unwrap()), server, impairment)
+        .expect("couldn't bind to address");
+    println!("Rusty Snake proxy listening on {}, forwarding to {}", proxy.local_addr(), server);
+    proxy.run();
+}
+
+fn is_server_address(value: String) -> Result<(), String> {
+    match value.to_socket_addrs().map(|mut addresses| addresses.next()) {
+        Ok(Some(_)) => Ok(()),
+        _ => Err(format!("'{}' is not a server address, it looks like 127.0.0.1:10000", value)),
+    }
+}
+
+fn is_percentage(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(()),
+        _ => Err(format!("'{}' is not a percentage from 0 to 100", value)),
+    }
+}
+
+fn is_millis(value: String) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("'{}' is not a number of milliseconds", value)),
+    }
+}
@@ -0,0 +1,28 @@
+use crate::game::{DIRECTION, Snake};
+
+pub fn calculate_direction(snake: &Snake, target_y: &i32, target_x: &i32, head_y: &i32, head_x: &i32) -> DIRECTION {
+    let mut direction = DIRECTION::Left;
+    let mut helper_flag = false;
+
+    if head_x > target_x {
+        direction = DIRECTION::Left;
+        if snake.direction == DIRECTION::Right {
+            helper_flag = true;
+        };
+    }
+    if head_x < target_x {
+        direction = DIRECTION::Right;
+        if snake.direction == DIRECTION::Left {
+            helper_flag = true;
+        };
+    }
+    if head_x == target_x || helper_flag {
+        if head_y < target_y {
+            direction = DIRECTION::Down;
+        };
+        if head_y > target_y {
+            direction = DIRECTION::Up;
+        };
+    }
+    direction
+}
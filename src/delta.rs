@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+
+use crate::game::{Field, Food};
+use crate::protocol::{DeltaFrame, SnakeDelta, UdpFrame};
+
+/* Every this many ticks everybody gets a full frame, whatever they acknowledged. */
+pub static KEYFRAME_INTERVAL: u64 = 30;
+/* Frames kept to build deltas on, an older baseline gets a full frame instead. */
+static HISTORY_LENGTH: usize = 32;
+
+/* The last frames sent or received, oldest first. */
+#[derive(Default)]
+pub struct FrameHistory {
+    frames: VecDeque<UdpFrame>,
+}
+
+impl FrameHistory {
+    pub fn push(&mut self, frame: UdpFrame) {
+        // A full frame after a reconnect may start over from an earlier tick.
+        self.frames.retain(|known| known.tick < frame.tick);
+        self.frames.push_back(frame);
+        while self.frames.len() > HISTORY_LENGTH {
+            self.frames.pop_front();
+        }
+    }
+
+    pub fn get(&self, tick: u64) -> Option<&UdpFrame> {
+        self.frames.iter().find(|frame| frame.tick == tick)
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /* Rebuilds a frame from a delta, as long as its baseline is still known. */
+    pub fn apply(&self, delta: DeltaFrame) -> Option<UdpFrame> {
+        let baseline = self.get(delta.baseline)?;
+        let mut snakes = delta.snakes.into_iter();
+        Some(UdpFrame {
+            tick: delta.tick,
+            snake1: apply_snake(&baseline.snake1, snakes.next()?)?,
+            snake2: apply_snake(&baseline.snake2, snakes.next()?)?,
+            foods: delta.foods.unwrap_or_else(|| baseline.foods.clone()),
+            inputs: delta.inputs,
+            refresh_timeout: delta.refresh_timeout,
+        })
+    }
+}
+
+/* The difference between a frame and the baseline, none when a snake did not simply move on from it. */
+pub fn diff(baseline: &UdpFrame, frame: &UdpFrame) -> Option<DeltaFrame> {
+    let same_food = baseline.foods.len() == frame.foods.len()
+        && baseline.foods.iter().zip(&frame.foods).all(|(old, new)| same_food(old, new));
+    Some(DeltaFrame {
+        tick: frame.tick,
+        baseline: baseline.tick,
+        snakes: vec![diff_snake(&baseline.snake1, &frame.snake1)?, diff_snake(&baseline.snake2, &frame.snake2)?],
+        foods: if same_food { None } else { Some(frame.foods.clone()) },
+        inputs: frame.inputs.clone(),
+        refresh_timeout: frame.refresh_timeout,
+    })
+}
+
+fn same_food(old: &Food, new: &Food) -> bool {
+    old.field == new.field && old.kind == new.kind
+}
+
+/* A snake only grows at the head and loses fields at the tail, so the old head
+ * is somewhere in the new body and everything behind it was there before. */
+fn diff_snake(baseline: &VecDeque<Field>, body: &VecDeque<Field>) -> Option<SnakeDelta> {
+    let old_head = baseline.front()?;
+    let added = body.iter().position(|field| field == old_head)?;
+    let kept = body.len() - added;
+    if kept > baseline.len() || !body.iter().skip(added).eq(baseline.iter().take(kept)) {
+        return None;
+    }
+    Some(SnakeDelta {
+        head: body.iter().take(added).cloned().collect(),
+        removed: (baseline.len() - kept) as u32,
+    })
+}
+
+fn apply_snake(baseline: &VecDeque<Field>, delta: SnakeDelta) -> Option<VecDeque<Field>> {
+    let kept = baseline.len().checked_sub(delta.removed as usize)?;
+    let mut body: VecDeque<Field> = delta.head.into_iter().collect();
+    body.extend(baseline.iter().take(kept).cloned());
+    Some(body)
+}
@@ -0,0 +1,113 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::network::{Interval, PacketStats};
+use crate::protocol::{decode, encode, ServerInfo, COMMANDS};
+
+/* Servers announce themselves to this port, the "find games" screen listens on it. */
+pub static DISCOVERY_PORT: u16 = 10001;
+pub static ANNOUNCE_INTERVAL: u64 = 1000;
+static PROBE_INTERVAL: u64 = 1000;
+/* A server not heard of for this long is gone from the list. */
+static SERVER_EXPIRY: Duration = Duration::from_secs(5);
+
+/* Tells every machine on the local network about the server. Without a network to
+ * broadcast to, at least the "find games" screen on this machine gets to see it. */
+pub fn announce(socket: &UdpSocket, info: ServerInfo) {
+    let packet = encode(&COMMANDS::Announce(info));
+    let broadcast = SocketAddr::new(Ipv4Addr::BROADCAST.into(), DISCOVERY_PORT);
+    if socket.send_to(&packet, broadcast).is_err() {
+        // Nobody may be listening at all, which is fine.
+        let _ = socket.send_to(&packet, SocketAddr::new(Ipv4Addr::LOCALHOST.into(), DISCOVERY_PORT));
+    }
+}
+
+pub struct DiscoveredServer {
+    /* Where the announcement came from, it is sent from the game port. */
+    pub address: SocketAddr,
+    pub info: ServerInfo,
+    pub ping: Option<Duration>,
+    last_seen: Instant,
+}
+
+/* Collects the servers announcing themselves and measures how far away they are. */
+pub struct Browser {
+    socket: UdpSocket,
+    started: Instant,
+    probe: Interval,
+    pub servers: Vec<DiscoveredServer>,
+    pub stats: PacketStats,
+}
+
+impl Browser {
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))?;
+        socket.set_nonblocking(true)?;
+        Ok(Browser {
+            socket,
+            started: Instant::now(),
+            probe: Interval::new(PROBE_INTERVAL),
+            servers: vec![],
+            stats: PacketStats::default(),
+        })
+    }
+
+    /* Reads the announcements and ping replies received so far, true when the list changed. */
+    pub fn poll(&mut self) -> bool {
+        let mut buffer = [0; 10000];
+        let mut changed = false;
+        loop {
+            let (length, source) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.stats.io_errors += 1;
+                    break;
+                },
+            };
+            match decode(&buffer[..length]) {
+                Ok(COMMANDS::Announce(info)) => {
+                    self.stats.received += 1;
+                    match self.servers.iter_mut().find(|server| server.address == source) {
+                        Some(server) => {
+                            server.info = info;
+                            server.last_seen = Instant::now();
+                        },
+                        None => self.servers.push(DiscoveredServer{
+                            address: source,
+                            info,
+                            ping: None,
+                            last_seen: Instant::now(),
+                        }),
+                    }
+                    changed = true;
+                },
+                Ok(COMMANDS::ProbeReply(sent)) => {
+                    self.stats.received += 1;
+                    let now = self.started.elapsed();
+                    if let Some(server) = self.servers.iter_mut().find(|server| server.address == source) {
+                        server.ping = Some(now.saturating_sub(Duration::from_micros(sent)));
+                        changed = true;
+                    }
+                },
+                Ok(_) => self.stats.unknown_peer += 1,
+                Err(_) => self.stats.malformed += 1,
+            }
+        }
+
+        let count = self.servers.len();
+        self.servers.retain(|server| server.last_seen.elapsed() < SERVER_EXPIRY);
+        changed |= count != self.servers.len();
+
+        if self.probe.ready() {
+            let sent = self.started.elapsed().as_micros() as u64;
+            for server in &self.servers {
+                if self.socket.send_to(&encode(&COMMANDS::Probe(sent)), server.address).is_err() {
+                    self.stats.io_errors += 1;
+                }
+            }
+        }
+        changed
+    }
+}
@@ -1,13 +1,34 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 use lazy_static::lazy_static;
 use maplit::hashmap;
-use rand::Rng;
+use ncurses::{KEY_DOWN, KEY_LEFT, KEY_RIGHT, KEY_UP};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use serde::{Deserialize, Serialize, Serializer};
 use serde::ser::SerializeStruct;
+use crate::grid::Grid;
 use crate::screen::{COLOR_PAIR_SNK1, COLOR_PAIR_SNK2};
 
+pub(crate) static REFRESH_TIMEOUT: i32 = 100;
+static MIN_REFRESH_TIMEOUT: i32 = 40;
+static MAX_REFRESH_TIMEOUT: i32 = 200;
+static SPEED_STEP: i32 = 20;
+static GOLDEN_FOOD_TICKS: u32 = 50;
+static MIN_SNAKE_LENGTH: usize = 2;
+/* When frames stop coming (the server paused the match), the prediction stops this far ahead. */
+static MAX_PREDICTED_STEPS: usize = 10;
+/* Lockstep peers compare checksums of their games every this many ticks. */
+pub static CHECKSUM_INTERVAL: u64 = 10;
+
 lazy_static! {
+    /* Keys steering the snake, the same on the client and the server. */
+    pub static ref DIRECTIONS: HashMap<&'static i32, DIRECTION> = hashmap! {
+        &KEY_DOWN => DIRECTION::Down,
+        &KEY_UP => DIRECTION::Up,
+        &KEY_LEFT => DIRECTION::Left,
+        &KEY_RIGHT => DIRECTION::Right,
+    };
     static ref FORBIDDEN_DIRECTIONS: HashMap<&'static DIRECTION, DIRECTION> = hashmap! {
         &DIRECTION::Up => DIRECTION::Down,
         &DIRECTION::Down => DIRECTION::Up,
@@ -16,11 +37,18 @@ lazy_static! {
     };
 }
 
+impl DIRECTION {
+    pub fn opposite(&self) -> DIRECTION {
+        FORBIDDEN_DIRECTIONS[self].clone()
+    }
+}
+
 #[derive(Clone)]
 pub enum MODE {
     Single,
     Server,
     Client,
+    Spectator,
 }
 
 pub struct Game {
@@ -29,17 +57,23 @@ pub struct Game {
     game_area_width: i32,
     game_area_start_y: i32,
     game_area_start_x: i32,
-    pub clear1_buffer: Vec<Field>,
-    pub clear2_buffer: Vec<Field>,
-    pub food: Field,
-    draw_food: fn(&i32, &i32),
+    pub clear1_buffer: VecDeque<Field>,
+    pub clear2_buffer: VecDeque<Field>,
+    pub foods: Vec<Food>,
+    grid: Grid,
+    food_count: usize,
+    tick: u64,
+    refresh_timeout: i32,
+    /* Everything random in the game comes from here, so a seed replays the same game. */
+    rng: StdRng,
+    draw_food: fn(&i32, &i32, &FoodKind),
     draw_block: fn(&i32, &i32, &i16),
     clear_field: fn(&i32, &i32),
 }
 
 impl Game {
     pub fn new(height: i32, width: i32,
-               draw_food: fn(&i32, &i32),
+               draw_food: fn(&i32, &i32, &FoodKind),
                draw_block: fn(&i32, &i32, &i16),
                clear_field: fn(&i32, &i32)) -> Self {
         Game {
@@ -48,16 +82,21 @@ impl Game {
             game_area_width: width,
             game_area_start_y: 0,
             game_area_start_x: 0,
-            clear1_buffer: vec![],
-            clear2_buffer: vec![],
-            food: Field{y: 0, x: 0},
+            clear1_buffer: VecDeque::new(),
+            clear2_buffer: VecDeque::new(),
+            foods: vec![],
+            grid: Grid::new(0, 0, height, width),
+            food_count: 1,
+            tick: 0,
+            refresh_timeout: REFRESH_TIMEOUT,
+            rng: StdRng::from_entropy(),
             draw_food,
             draw_block,
             clear_field,
         }
     }
 
-    pub fn draw_snake(&mut self, buffer1: Vec<Field>, buffer2: Vec<Field>) {
+    pub fn draw_snake(&mut self, buffer1: VecDeque<Field>, buffer2: VecDeque<Field>) {
         for field in &self.clear1_buffer {
             (self.clear_field)(&(field.y + self.game_area_start_y),
                                &(field.x + self.game_area_start_x));
@@ -79,10 +118,10 @@ impl Game {
         self.clear2_buffer = buffer2;
     }
 
-    pub fn transform_coords(&self, buffer: &mut Vec<Field>) {
-        for mut field in buffer {
-            field.y = field.y - self.game_area_start_y;
-            field.x = field.x - self.game_area_start_x;
+    pub fn transform_coords(&self, buffer: &mut VecDeque<Field>) {
+        for field in buffer {
+            field.y -= self.game_area_start_y;
+            field.x -= self.game_area_start_x;
         }
     }
 
@@ -97,89 +136,259 @@ impl Game {
     pub fn set_start(&mut self, start_y: i32, start_x: i32) {
         self.game_area_start_y = start_y;
         self.game_area_start_x = start_x;
+        self.grid = Grid::new(start_y, start_x, self.game_area_height, self.game_area_width);
     }
 
-    pub fn check_food(&mut self, snake: &mut Snake) {
-        if snake.body[0] == self.food {
-            self.init_food(&snake.body, &snake.body); // fix this...
+    /* Marks the initial snake bodies on the occupancy grid. */
+    pub fn init_grid(&mut self, snakes: &[Snake]) {
+        for snake in snakes {
+            for field in &snake.body {
+                self.grid.occupy(field);
+            }
         }
-        else {
-            let tail = snake.body.pop().unwrap();
-            (self.clear_field)(&tail.y, &tail.x);
+    }
+
+    /* Starts the game over from the snakes' starting positions. Two games restarted with the
+     * same snakes and seed and given the same directions every tick stay the same. */
+    pub fn restart(&mut self, snakes: &[Snake], seed: u64) {
+        for food in &self.foods {
+            (self.clear_field)(&food.field.y, &food.field.x);
         }
+        self.foods.clear();
+        self.grid = Grid::new(self.game_area_start_y, self.game_area_start_x,
+                              self.game_area_height, self.game_area_width);
+        self.tick = 0;
+        self.refresh_timeout = REFRESH_TIMEOUT;
+        self.rng = StdRng::seed_from_u64(seed);
+        self.init_grid(snakes);
+        self.init_food();
     }
 
-    pub fn init_food (&mut self, forbidden1: &Vec<Field>, forbidden2: &Vec<Field>) {
-        self.food = self.new_food(forbidden1, forbidden2);
-        (self.draw_food)(&self.food.y, &self.food.x);
+    /* One tick with the given direction for every snake, the same on every lockstep peer. */
+    pub fn step(&mut self, snakes: &mut [Snake], directions: &[DIRECTION]) {
+        for (snake, direction) in snakes.iter_mut().zip(directions) {
+            snake.change_direction(direction);
+        }
+        self.move_snakes(snakes);
+        self.check_food(snakes);
+        self.update_food();
     }
 
-    pub fn set_food (&mut self, y: i32, x: i32) {
-        (self.clear_field)(&(self.food.y + self.game_area_start_y),
-                           &(self.food.x + self.game_area_start_x));
-        self.food.y = y;
-        self.food.x = x;
-        (self.draw_food)(&(self.food.y + self.game_area_start_y),
-                         &(self.food.x + self.game_area_start_x));
+    /* Fingerprint of everything the simulation depends on, relative to the game area so
+     * it does not matter where on the screen the game is. FNV-1a, the same on every build. */
+    pub fn checksum(&self, snakes: &[Snake]) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut add = |value: i64| {
+            for byte in value.to_le_bytes().iter() {
+                hash ^= u64::from(*byte);
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        };
+        add(self.tick as i64);
+        add(i64::from(self.refresh_timeout));
+        for snake in snakes {
+            add(snake.body.len() as i64);
+            add(i64::from(snake.growth));
+            for field in &snake.body {
+                add(i64::from(field.y - self.game_area_start_y));
+                add(i64::from(field.x - self.game_area_start_x));
+            }
+        }
+        for food in &self.foods {
+            add(i64::from(food.field.y - self.game_area_start_y));
+            add(i64::from(food.field.x - self.game_area_start_x));
+            add(food.kind as i64);
+            add(food.ttl.map_or(-1, i64::from));
+        }
+        hash
     }
 
-    pub fn get_food (&mut self) -> (i32, i32) {
-        (self.food.y, self.food.x)
+    /* No free field is left and all the food is eaten, there is nothing more to play for.
+     * Fields holding food don't count as free, but the snakes can still get to them. */
+    pub fn is_board_full(&self) -> bool {
+        self.grid.free_fields() == 0 && self.foods.is_empty()
     }
 
-    pub fn get_food_win (&mut self) -> (i32, i32) {
-        (self.food.y - self.game_area_start_y, self.food.x - self.game_area_start_x)
+    /* Number of simulation steps done so far. */
+    pub fn get_tick(&self) -> u64 {
+        self.tick
     }
 
-    pub fn new_food (&self, forbidden1: &Vec<Field>, forbidden2: &Vec<Field>) -> Field {
-        // Food cannot appear on snake!
-        let mut field: Field;
-        loop {
-            field = Field{y: rand::thread_rng().gen_range(self.game_area_start_y + 1,
-                                                          self.game_area_start_y + self.game_area_height - 1),
-                          x: rand::thread_rng().gen_range(self.game_area_start_x + 1,
-                                                          self.game_area_start_x + self.game_area_width - 1)};
-            if !(forbidden1.contains(&field)) && !(forbidden2.contains(&field)) {
-               break;
+    pub fn move_snakes(&mut self, snakes: &mut [Snake]) {
+        self.tick += 1;
+        for snake in snakes.iter_mut() {
+            snake.move_snake();
+            self.grid.occupy(&snake.body[0]);
+        }
+    }
+
+    pub fn get_area_size(&self) -> (i32, i32) {
+        (self.game_area_height, self.game_area_width)
+    }
+
+    pub fn set_area_size(&mut self, height: i32, width: i32) {
+        self.game_area_height = height;
+        self.game_area_width = width;
+        self.set_start(self.game_area_start_y, self.game_area_start_x);
+    }
+
+    pub fn get_food_count(&self) -> usize {
+        self.food_count
+    }
+
+    pub fn get_refresh_timeout(&self) -> i32 {
+        self.refresh_timeout
+    }
+
+    /* Clients follow the speed of the server's game. */
+    pub fn set_refresh_timeout(&mut self, refresh_timeout: i32) {
+        self.refresh_timeout = refresh_timeout.clamp(MIN_REFRESH_TIMEOUT, MAX_REFRESH_TIMEOUT);
+    }
+
+    pub fn set_food_count(&mut self, count: usize) {
+        self.food_count = count.max(1);
+    }
+
+    pub fn check_food(&mut self, snakes: &mut [Snake]) {
+        for snake in snakes.iter_mut() {
+            if let Some(index) = self.foods.iter().position(|food| food.field == snake.body[0]) {
+                let food = self.foods.remove(index);
+                self.grid.set_food(&food.field, false);
+                self.eat_food(snake, &food.kind);
             }
-        };
-        field
+            self.move_tail(snake);
+        }
+        self.init_food();
     }
 
-    pub fn check_collisions(&self, snakes: &Vec<Snake>) -> bool {
-        let mut self_collision = false;
-        for snake in snakes {
-            let head = snake.body.get(0).unwrap();
-            for element in snake.body[1..].iter() {
-                if element == head {
-                    self_collision = true;
-                }
+    fn eat_food(&mut self, snake: &mut Snake, kind: &FoodKind) {
+        match kind {
+            FoodKind::Normal => snake.growth += 1,
+            FoodKind::Bonus => snake.growth += 3,
+            FoodKind::Shrink => snake.growth -= 2,
+            FoodKind::Golden => snake.growth += 5,
+            FoodKind::SpeedUp => {
+                snake.growth += 1;
+                self.refresh_timeout = (self.refresh_timeout - SPEED_STEP).max(MIN_REFRESH_TIMEOUT);
+            },
+            FoodKind::SlowDown => {
+                snake.growth += 1;
+                self.refresh_timeout = (self.refresh_timeout + SPEED_STEP).min(MAX_REFRESH_TIMEOUT);
+            },
+        }
+    }
+
+    fn move_tail(&mut self, snake: &mut Snake) {
+        if snake.growth > 0 {
+            snake.growth -= 1;
+            return;
+        }
+
+        let tail = snake.remove_tail().unwrap();
+        self.release_field(&tail);
+
+        // Shrinking never takes the snake below its starting length.
+        while snake.growth < 0 && snake.body.len() > MIN_SNAKE_LENGTH {
+            let tail = snake.remove_tail().unwrap();
+            self.release_field(&tail);
+            snake.growth += 1;
+        }
+        snake.growth = snake.growth.max(0);
+    }
+
+    fn release_field(&mut self, field: &Field) {
+        self.grid.release(field);
+        // Another snake may already have moved its head onto this field.
+        if self.grid.segments(field) == 0 {
+            (self.clear_field)(&field.y, &field.x);
+        }
+    }
+
+    /* Counts down timed food and replaces it once it expires. */
+    pub fn update_food(&mut self) {
+        let clear_field = self.clear_field;
+        let grid = &mut self.grid;
+        self.foods.retain(|food| {
+            if let Some(0) = food.ttl {
+                grid.set_food(&food.field, false);
+                clear_field(&food.field.y, &food.field.x);
+                return false;
+            }
+            true
+        });
+        for food in &mut self.foods {
+            if let Some(ttl) = food.ttl {
+                food.ttl = Some(ttl - 1);
             }
         }
+        self.init_food();
+    }
 
-        let mut game_area_collision = false;
-        for snake in snakes {
-            if snake.body[0].y == self.game_area_start_y ||
-                snake.body[0].y == self.game_area_start_y + self.game_area_height - 1 ||
-                snake.body[0].x == self.game_area_start_x ||
-                snake.body[0].x == self.game_area_start_x + self.game_area_width - 1 {
-                game_area_collision = true;
+    /* Tops the board up to the configured number of food items, as far as free fields allow. */
+    pub fn init_food (&mut self) {
+        while self.foods.len() < self.food_count {
+            match self.new_food() {
+                Some(food) => {
+                    self.grid.set_food(&food.field, true);
+                    (self.draw_food)(&food.field.y, &food.field.x, &food.kind);
+                    self.foods.push(food);
+                },
+                None => break,
             }
         }
+    }
 
-        let mut snakes_collision = false;
-        if snakes[0].body.contains(&snakes[1].body[0]) {
-            snakes_collision = true;
+    pub fn set_foods (&mut self, foods: Vec<Food>) {
+        for food in &self.foods {
+            (self.clear_field)(&(food.field.y + self.game_area_start_y),
+                               &(food.field.x + self.game_area_start_x));
         }
-        if snakes[1].body.contains(&snakes[0].body[0]) {
-            snakes_collision = true;
+        self.foods = foods;
+        for food in &self.foods {
+            (self.draw_food)(&(food.field.y + self.game_area_start_y),
+                             &(food.field.x + self.game_area_start_x), &food.kind);
         }
+    }
+
+    /* Position of the food closest to the given field. */
+    pub fn get_nearest_food (&self, y: &i32, x: &i32) -> (i32, i32) {
+        self.foods.iter()
+            .min_by_key(|food| (food.field.y - y).abs() + (food.field.x - x).abs())
+            .map(|food| (food.field.y, food.field.x))
+            .unwrap_or((*y, *x))
+    }
+
+    pub fn get_foods_win (&self) -> Vec<Food> {
+        self.foods.iter()
+            .map(|food| Food{field: Field{y: food.field.y - self.game_area_start_y,
+                                          x: food.field.x - self.game_area_start_x},
+                             kind: food.kind,
+                             ttl: food.ttl})
+            .collect()
+    }
+
+    pub fn new_food (&mut self) -> Option<Food> {
+        // Food cannot appear on snake or on other food!
+        let field = self.grid.random_free(&mut self.rng)?;
+
+        let kind = FoodKind::random(&mut self.rng);
+        let ttl = match kind {
+            FoodKind::Golden => Some(GOLDEN_FOOD_TICKS),
+            _ => None,
+        };
+        Some(Food{field, kind, ttl})
+    }
 
-        self_collision || game_area_collision || snakes_collision
+    /* A head collides when it hits the border or shares its field with any other segment. */
+    pub fn check_collisions(&self, snakes: &[Snake]) -> bool {
+        snakes.iter().any(|snake| {
+            let head = &snake.body[0];
+            self.grid.is_wall(head) || self.grid.segments(head) > 1
+        })
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum DIRECTION {
     Down,
     Up,
@@ -193,6 +402,51 @@ pub struct Field {
     pub(crate) x: i32,
 }
 
+impl Field {
+    pub fn new(y: i32, x: i32) -> Self {
+        Field{y, x}
+    }
+
+    pub fn y(&self) -> i32 {
+        self.y
+    }
+
+    pub fn x(&self) -> i32 {
+        self.x
+    }
+}
+
+/* Every food kind has a different effect on the snake that eats it. */
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FoodKind {
+    Normal,
+    Bonus,
+    Shrink,
+    SpeedUp,
+    SlowDown,
+    Golden,
+}
+
+impl FoodKind {
+    pub fn random<R: Rng>(rng: &mut R) -> Self {
+        match rng.gen_range(0, 100) {
+            0..=59 => FoodKind::Normal,
+            60..=71 => FoodKind::Bonus,
+            72..=81 => FoodKind::Shrink,
+            82..=88 => FoodKind::SpeedUp,
+            89..=95 => FoodKind::SlowDown,
+            _ => FoodKind::Golden,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Food {
+    pub(crate) field: Field,
+    pub(crate) kind: FoodKind,
+    pub(crate) ttl: Option<u32>,
+}
+
 impl PartialEq for Field {
     fn eq(&self, other: &Self) -> bool {
         self.y == other.y && self.x == other.x
@@ -213,8 +467,9 @@ impl Serialize for Field {
 }
 
 pub struct Snake {
-    pub(crate) body: Vec<Field>,
+    pub(crate) body: VecDeque<Field>,
     pub(crate) direction: DIRECTION,
+    pub(crate) growth: i32,
     draw_block: fn(&i32, &i32, &i16),
     color: i16,
 }
@@ -222,26 +477,46 @@ pub struct Snake {
 impl Snake {
     pub fn new(head_y: i32, head_x: i32, direction: DIRECTION,
                draw_block: fn(&i32, &i32, &i16), color: i16) -> Self {
-        let mut snake = Snake {
-            body: Vec::from(vec![Field{y: head_y, x: head_x}]),
-            direction,
-            draw_block,
-            color,
-        };
+        let mut snake = Snake::with_body(VecDeque::from(vec![Field{y: head_y, x: head_x}]), direction,
+                                         draw_block, color);
         if snake.direction == DIRECTION::Right {
-            snake.body.push(Field{y: head_y, x: head_x - 1});
+            snake.body.push_back(Field{y: head_y, x: head_x - 1});
         }
         else {
-            snake.body.push(Field{y: head_y, x: head_x + 1});
+            snake.body.push_back(Field{y: head_y, x: head_x + 1});
         }
         snake
     }
 
+    /* A snake laid out any way, the head first. */
+    pub fn with_body(body: VecDeque<Field>, direction: DIRECTION,
+                     draw_block: fn(&i32, &i32, &i16), color: i16) -> Self {
+        Snake {
+            body,
+            direction,
+            growth: 0,
+            draw_block,
+            color,
+        }
+    }
+
+    pub fn body(&self) -> &VecDeque<Field> {
+        &self.body
+    }
+
+    pub fn remove_tail(&mut self) -> Option<Field> {
+        self.body.pop_back()
+    }
+
+    /* A turn is neither the way the snake already goes nor straight back into its body.
+     * The server takes a player's input by this rule, and the prediction does the same. */
+    pub fn can_turn(&self, direction: &DIRECTION) -> bool {
+        *direction != self.direction && *direction != self.direction.opposite()
+    }
+
     pub fn change_direction(&mut self, direction: &DIRECTION) {
-        if let Some(forbidden_direction) = FORBIDDEN_DIRECTIONS.get(&self.direction) {
-            if forbidden_direction != direction {
-                self.direction = direction.clone();
-            }
+        if self.can_turn(direction) {
+            self.direction = direction.clone();
         }
     }
 
@@ -251,7 +526,7 @@ impl Snake {
 
     pub fn move_snake(&mut self) {
         let new_head = Field{y: self.body[0].y, x: self.body[0].x};
-        self.body.insert(0, new_head);
+        self.body.push_front(new_head);
 
         match self.direction {
             DIRECTION::Down => {self.body[0].y += 1},
@@ -262,3 +537,127 @@ impl Snake {
         (self.draw_block)(&self.body[0].y, &self.body[0].x, &self.color);
     }
 }
+
+fn no_draw(_y: &i32, _x: &i32, _color: &i16) {}
+
+/* The client's own snake as it will be once the server has applied the inputs still on their way.
+ *
+ * Every input moves the snake one field, like a server step does, and turns it at most once
+ * by the same rule. An authoritative frame replaces the snake and the inputs the server's
+ * steps already used are dropped, the rest are applied again on top of it. Food is left to
+ * the server, so the predicted snake only grows when a frame says so. */
+pub struct Prediction {
+    snake: Snake,
+    pending: VecDeque<(u64, Option<DIRECTION>)>,
+}
+
+impl Prediction {
+    pub fn new(body: VecDeque<Field>) -> Self {
+        let direction = Prediction::heading(&body).unwrap_or(DIRECTION::Left);
+        Prediction {
+            snake: Snake::with_body(body, direction, no_draw, 0),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /* Where the snake goes, seen from its first two fields. */
+    fn heading(body: &VecDeque<Field>) -> Option<DIRECTION> {
+        let (head, neck) = (body.front()?, body.get(1)?);
+        match (head.y - neck.y, head.x - neck.x) {
+            (1, _) => Some(DIRECTION::Down),
+            (-1, _) => Some(DIRECTION::Up),
+            (_, 1) => Some(DIRECTION::Right),
+            (_, -1) => Some(DIRECTION::Left),
+            _ => None,
+        }
+    }
+
+    fn apply(snake: &mut Snake, direction: &Option<DIRECTION>) {
+        if let Some(direction) = direction {
+            snake.change_direction(direction);
+        }
+        snake.move_snake();
+        snake.remove_tail();
+    }
+
+    /* The input just sent to the server shows right away. Once the prediction is as far ahead
+     * as it goes the snake stands still, and the oldest input makes room for the new one. */
+    pub fn step(&mut self, seq: u64, direction: Option<DIRECTION>) {
+        if self.pending.len() >= MAX_PREDICTED_STEPS {
+            self.pending.pop_front();
+        }
+        else if !self.snake.body.is_empty() {
+            Prediction::apply(&mut self.snake, &direction);
+        }
+        self.pending.push_back((seq, direction));
+    }
+
+    pub fn reconcile(&mut self, body: VecDeque<Field>, acknowledged: u64) {
+        if let Some(direction) = Prediction::heading(&body) {
+            self.snake.direction = direction;
+        }
+        self.snake.body = body;
+        while self.pending.front().is_some_and(|(seq, _)| *seq <= acknowledged) {
+            self.pending.pop_front();
+        }
+        for (_, direction) in &self.pending {
+            Prediction::apply(&mut self.snake, direction);
+        }
+    }
+
+    pub fn body(&self) -> &VecDeque<Field> {
+        &self.snake.body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Three fields long, heading right with the head at 5,5.
+    fn body() -> VecDeque<Field> {
+        (3..6).rev().map(|x| Field{y: 5, x}).collect()
+    }
+
+    fn head(prediction: &Prediction) -> (i32, i32) {
+        (prediction.body()[0].y, prediction.body()[0].x)
+    }
+
+    #[test]
+    fn prediction_turns_like_the_server() {
+        let mut prediction = Prediction::new(body());
+        // Straight back into the body is ignored, so is the way the snake already goes.
+        prediction.step(1, Some(DIRECTION::Left));
+        prediction.step(2, Some(DIRECTION::Right));
+        assert_eq!(head(&prediction), (5, 7));
+        prediction.step(3, Some(DIRECTION::Down));
+        assert_eq!(head(&prediction), (6, 7));
+    }
+
+    #[test]
+    fn prediction_replays_what_the_server_has_not_applied() {
+        let mut prediction = Prediction::new(body());
+        prediction.step(1, None);
+        prediction.step(2, Some(DIRECTION::Up));
+        prediction.step(3, None);
+
+        // The server's step went by the first input only.
+        let server: VecDeque<Field> = (4..7).rev().map(|x| Field{y: 5, x}).collect();
+        prediction.reconcile(server, 1);
+        assert_eq!(head(&prediction), (3, 6));
+        assert_eq!(prediction.pending.len(), 2);
+    }
+
+    #[test]
+    fn prediction_stops_while_the_server_is_silent() {
+        let mut prediction = Prediction::new(body());
+        for seq in 1..100 {
+            prediction.step(seq, None);
+        }
+        assert_eq!(prediction.pending.len(), MAX_PREDICTED_STEPS);
+        assert_eq!(head(&prediction), (5, 5 + MAX_PREDICTED_STEPS as i32));
+
+        prediction.reconcile(body(), 0);
+        assert_eq!(head(&prediction), (5, 5 + MAX_PREDICTED_STEPS as i32));
+    }
+}
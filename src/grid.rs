@@ -0,0 +1,208 @@
+use rand::Rng;
+
+use crate::game::Field;
+
+const NOT_FREE: usize = usize::MAX;
+
+#[derive(Clone, Copy, Default)]
+struct Cell {
+    segments: u8,
+    food: bool,
+}
+
+/* Occupancy of every field inside the game area border.
+ *
+ * Free fields are additionally kept in a list with a reverse index, so
+ * occupying, releasing and picking a random free field are all O(1). */
+pub struct Grid {
+    start_y: i32,
+    start_x: i32,
+    height: i32,
+    width: i32,
+    cells: Vec<Cell>,
+    free: Vec<usize>,
+    free_position: Vec<usize>,
+}
+
+impl Grid {
+    /* Height and width include the border, the same as the game area. */
+    pub fn new(start_y: i32, start_x: i32, height: i32, width: i32) -> Self {
+        let inner_height = (height - 2).max(0);
+        let inner_width = (width - 2).max(0);
+        let size = (inner_height * inner_width) as usize;
+
+        Grid {
+            start_y: start_y + 1,
+            start_x: start_x + 1,
+            height: inner_height,
+            width: inner_width,
+            cells: vec![Cell::default(); size],
+            free: (0..size).collect(),
+            free_position: (0..size).collect(),
+        }
+    }
+
+    fn index(&self, field: &Field) -> Option<usize> {
+        let y = field.y - self.start_y;
+        let x = field.x - self.start_x;
+        if y < 0 || y >= self.height || x < 0 || x >= self.width {
+            return None;
+        }
+        Some((y * self.width + x) as usize)
+    }
+
+    fn field(&self, index: usize) -> Field {
+        let index = index as i32;
+        Field{y: self.start_y + index / self.width, x: self.start_x + index % self.width}
+    }
+
+    /* Keeps the free list in line with the state of a single cell. */
+    fn sync_free(&mut self, index: usize) {
+        let cell = self.cells[index];
+        let is_free = cell.segments == 0 && !cell.food;
+        let position = self.free_position[index];
+
+        if is_free && position == NOT_FREE {
+            self.free_position[index] = self.free.len();
+            self.free.push(index);
+        }
+        else if !is_free && position != NOT_FREE {
+            let last = *self.free.last().unwrap();
+            self.free.swap_remove(position);
+            if last != index {
+                self.free_position[last] = position;
+            }
+            self.free_position[index] = NOT_FREE;
+        }
+    }
+
+    pub fn is_wall(&self, field: &Field) -> bool {
+        self.index(field).is_none()
+    }
+
+    /* Number of snake segments on the field, walls are never occupied. */
+    pub fn segments(&self, field: &Field) -> u8 {
+        self.index(field).map_or(0, |index| self.cells[index].segments)
+    }
+
+    pub fn occupy(&mut self, field: &Field) {
+        if let Some(index) = self.index(field) {
+            self.cells[index].segments = self.cells[index].segments.saturating_add(1);
+            self.sync_free(index);
+        }
+    }
+
+    pub fn release(&mut self, field: &Field) {
+        if let Some(index) = self.index(field) {
+            self.cells[index].segments = self.cells[index].segments.saturating_sub(1);
+            self.sync_free(index);
+        }
+    }
+
+    pub fn set_food(&mut self, field: &Field, food: bool) {
+        if let Some(index) = self.index(field) {
+            self.cells[index].food = food;
+            self.sync_free(index);
+        }
+    }
+
+    pub fn free_fields(&self) -> usize {
+        self.free.len()
+    }
+
+    /* Uniformly random field with neither a snake nor food on it. The free list is kept
+     * in the same order given the same moves, so a seeded generator picks the same field. */
+    pub fn random_free<R: Rng>(&self, rng: &mut R) -> Option<Field> {
+        if self.free.is_empty() {
+            return None;
+        }
+        let index = self.free[rng.gen_range(0, self.free.len())];
+        Some(self.field(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+
+    // 3x4 free fields inside the border, the top left one at 1,1.
+    fn grid() -> Grid {
+        Grid::new(0, 0, 5, 6)
+    }
+
+    #[test]
+    fn occupy_and_release_count_segments() {
+        let mut grid = grid();
+        let field = Field{y: 1, x: 1};
+        assert_eq!(grid.free_fields(), 12);
+
+        grid.occupy(&field);
+        grid.occupy(&field);
+        assert_eq!(grid.segments(&field), 2);
+        assert_eq!(grid.free_fields(), 11);
+
+        // Free again only once the last segment is gone.
+        grid.release(&field);
+        assert_eq!(grid.free_fields(), 11);
+        grid.release(&field);
+        assert_eq!(grid.segments(&field), 0);
+        assert_eq!(grid.free_fields(), 12);
+    }
+
+    #[test]
+    fn walls_are_never_occupied() {
+        let mut grid = grid();
+        let wall = Field{y: 0, x: 3};
+        assert!(grid.is_wall(&wall));
+        assert!(!grid.is_wall(&Field{y: 3, x: 4}));
+        assert!(grid.is_wall(&Field{y: 4, x: 4}));
+
+        grid.occupy(&wall);
+        assert_eq!(grid.segments(&wall), 0);
+        assert_eq!(grid.free_fields(), 12);
+    }
+
+    #[test]
+    fn food_takes_a_field_until_it_is_gone() {
+        let mut grid = grid();
+        let field = Field{y: 2, x: 3};
+        grid.set_food(&field, true);
+        assert_eq!(grid.free_fields(), 11);
+
+        // A snake eating the food keeps the field taken.
+        grid.occupy(&field);
+        grid.set_food(&field, false);
+        assert_eq!(grid.free_fields(), 11);
+        grid.release(&field);
+        assert_eq!(grid.free_fields(), 12);
+    }
+
+    #[test]
+    fn random_free_picks_only_free_fields() {
+        let mut grid = grid();
+        let mut rng = StdRng::seed_from_u64(1);
+        for y in 1..4 {
+            for x in 1..5 {
+                if (y, x) != (3, 2) {
+                    grid.occupy(&Field{y, x});
+                }
+            }
+        }
+        for _ in 0..10 {
+            assert!(grid.random_free(&mut rng) == Some(Field{y: 3, x: 2}));
+        }
+
+        grid.set_food(&Field{y: 3, x: 2}, true);
+        assert!(grid.random_free(&mut rng).is_none());
+
+        grid.release(&Field{y: 1, x: 1});
+        grid.release(&Field{y: 2, x: 4});
+        for _ in 0..20 {
+            let field = grid.random_free(&mut rng).unwrap();
+            assert!(field == Field{y: 1, x: 1} || field == Field{y: 2, x: 4});
+        }
+    }
+}
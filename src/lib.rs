@@ -0,0 +1,19 @@
+#![allow(clippy::upper_case_acronyms)]
+
+extern crate bincode;
+extern crate ncurses;
+extern crate serde;
+
+pub mod auth;
+pub mod bot;
+pub mod delta;
+pub mod discovery;
+pub mod game;
+pub mod grid;
+pub mod network;
+pub mod protocol;
+pub mod proxy;
+pub mod screen;
+pub mod server;
+pub mod shutdown;
+pub mod transport;
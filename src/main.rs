@@ -1,47 +1,38 @@
-extern crate bincode;
 extern crate ncurses;
-extern crate serde;
 
-use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::io;
-use std::thread::sleep;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::process;
+use std::str::FromStr;
+use std::thread::{self, sleep};
 use std::time::{Duration, Instant};
 
 use clap::{App, Arg};
-use lazy_static::lazy_static;
-use maplit::hashmap;
 use ncurses::*;
-use serde::{Deserialize, Serialize};
-
-use crate::game::{DIRECTION, Field, Game, MODE, Snake};
-use crate::network::{init_network, send_endgame, UdpFrame};
-use crate::screen::{create_status_window, create_game_area, create_ending_window, destroy_window,
-                    GAME_AREA_HEIGHT, GAME_AREA_WIDTH, update_score, init_window_colors,
-                    COLOR_PAIR_FOOD, print_ending_info};
-
-mod network;
-mod game;
-mod screen;
-
-static REFRESH_TIMEOUT: i32 = 100;
-
-lazy_static! {
-    static ref DIRECTIONS: HashMap<&'static i32, DIRECTION> = hashmap! {
-        &KEY_DOWN => DIRECTION::Down,
-        &KEY_UP => DIRECTION::Up,
-        &KEY_LEFT => DIRECTION::Left,
-        &KEY_RIGHT => DIRECTION::Right,
-    };
-}
 
-#[derive(Serialize, Deserialize)]
-enum COMMANDS {
-    Connect,
-    Endgame,
-    Key(i32),
-    ServerData(UdpFrame),
-}
+use rusty_snake::auth::Key;
+use rusty_snake::bot::calculate_direction;
+use rusty_snake::delta::FrameHistory;
+use rusty_snake::discovery::{Browser, DISCOVERY_PORT};
+use rusty_snake::game::{CHECKSUM_INTERVAL, DIRECTION, DIRECTIONS, Field, Game, MODE, Prediction};
+use rusty_snake::network::{init_network, listen_address, sanitize_name, Bandwidth, ConnectError, Connection,
+                           EndgameNotice, Interval, Latency, SequenceFilter, TickRate, MAX_CHAT_LENGTH, MAX_NAME_LENGTH,
+                           RECONNECT_RETRY};
+use rusty_snake::protocol::{ChatMessage, GameSettings, RoomInfo, RoomStatus, UdpFrame, COMMANDS, PLAYER_COLORS, PROTOCOL_VERSION};
+use rusty_snake::screen::{create_status_window, create_game_area, create_ending_window, create_debug_window,
+                          destroy_window, GAME_AREA_HEIGHT, GAME_AREA_WIDTH, update_score, init_window_colors,
+                          print_ending_info, print_match_result, print_food, print_block, print_space,
+                          update_packet_stats, print_debug_overlay, print_latency, print_session, print_status_message,
+                          print_lobby, print_room, print_servers, clear_game_area, set_player_colors, EndReason,
+                          create_chat_window, print_chat, InputEvent, TextInput};
+use rusty_snake::server::{Server, ServerConfig};
+use rusty_snake::shutdown;
+use rusty_snake::transport::TransportKind;
+
+/* Chat messages kept for scrolling back, more than the chat window shows. */
+static CHAT_HISTORY: usize = 50;
 
 fn main() {
     let matches = App::new("Rusty Snake")
@@ -52,208 +43,496 @@ fn main() {
             .short("c")
             .long("conn")
             .takes_value(true)
+            .validator(is_server_address)
             .help("Address of Snake server to connect"))
 
+        .arg(Arg::with_name("lan")
+            .short("l")
+            .long("lan")
+            .help("Look for games on the local network")
+            .conflicts_with_all(&["connect", "server", "dedicated"]))
+
         .arg(Arg::with_name("server")
             .short("s")
             .long("server")
             .help("Run as multiplayer game server")
             .requires("port"))
 
+        .arg(Arg::with_name("dedicated")
+            .short("d")
+            .long("dedicated")
+            .help("Run a headless server hosting many matches at once")
+            .requires("port")
+            .conflicts_with_all(&["server", "connect"]))
+
+        .arg(Arg::with_name("lockstep")
+            .short("L")
+            .long("lockstep")
+            .help("Send only the players' inputs, every client simulates the matches itself")
+            .conflicts_with_all(&["connect", "lan"]))
+
+        .arg(Arg::with_name("server-name")
+            .short("N")
+            .long("server-name")
+            .takes_value(true)
+            .help("Server name shown to the local network (default: Rusty Snake)"))
+
+        .arg(Arg::with_name("transport")
+            .long("transport")
+            .takes_value(true)
+            .possible_values(&["udp", "tcp", "ws"])
+            .conflicts_with("lan")
+            .help("How the server and the clients talk, ws lets browsers in (default: udp)"))
+
+        .arg(Arg::with_name("password")
+            .short("P")
+            .long("password")
+            .takes_value(true)
+            .help("Shared secret of a private server, clients need the same one to get in"))
+
+        .arg(Arg::with_name("food")
+            .short("f")
+            .long("food")
+            .takes_value(true)
+            .validator(parses::<usize>("number"))
+            .help("Number of food items on the board (default: one per player plus one)"))
+
+        .arg(Arg::with_name("name")
+            .short("n")
+            .long("name")
+            .takes_value(true)
+            .help("Player name shown to the other player"))
+
+        .arg(Arg::with_name("timeout")
+            .short("t")
+            .long("timeout")
+            .takes_value(true)
+            .validator(parses::<u64>("number of seconds"))
+            .help("Seconds without any message before the opponent counts as disconnected (default: 5)"))
+
+        .arg(Arg::with_name("grace")
+            .short("g")
+            .long("grace")
+            .takes_value(true)
+            .validator(parses::<u64>("number of seconds"))
+            .help("Seconds to wait for a disconnected opponent to come back (default: 30)"))
+
+        .arg(Arg::with_name("rejoin")
+            .short("r")
+            .long("rejoin")
+            .takes_value(true)
+            .requires("connect")
+            .validator(is_session)
+            .help("Session shown in the status window, takes the place back in a running match"))
+
         .arg(Arg::with_name("port")
             .short("p")
             .long("port")
             .takes_value(true)
+            .validator(parses::<u16>("port number"))
             .help("Server port number"))
 
+        .arg(Arg::with_name("bind")
+            .short("b")
+            .long("bind")
+            .takes_value(true)
+            .validator(is_bind_address)
+            .help("Local address to bind to, the port may be left out (default: any address)"))
+
         .get_matches();
 
+    /* From here on a crash or Ctrl-C leaves the terminal usable. */
+    shutdown::install();
+
     /* Initialize game data. */
     let mut game = Game::new(GAME_AREA_HEIGHT, GAME_AREA_WIDTH, print_food, print_block, print_space);
     let mut snakes = vec![];
     let mut udp_buffer = [0; 10000];
+    let mut frame_filter = SequenceFilter::default();
+    let mut frames = FrameHistory::default();
+    let mut input_seq: u64 = 0;
 
     /* Get input from user. */
-    let address = matches.value_of("connect").unwrap_or("0.0.0.0:10000");
+    let mut address = String::from(matches.value_of("connect").unwrap_or("0.0.0.0:10000"));
     let server_port = matches.value_of("port").unwrap_or("10000");
+    let bind = matches.value_of("bind");
     let is_server = matches.is_present("server");
+    let is_client = matches.is_present("connect") || matches.is_present("lan");
+    let food_count = matches.value_of("food")
+        .map(|count| count.parse::<usize>().expect("Unable to parse food count"));
+    let peer_timeout = matches.value_of("timeout")
+        .map(|seconds| seconds.parse::<u64>().expect("Unable to parse timeout"))
+        .unwrap_or(5);
+    let grace_period = matches.value_of("grace")
+        .map(|seconds| seconds.parse::<u64>().expect("Unable to parse grace period"))
+        .unwrap_or(30);
+    let transport = matches.value_of("transport")
+        .map(|transport| transport.parse::<TransportKind>().expect("Unable to parse transport"))
+        .unwrap_or(TransportKind::Udp);
+    let password = matches.value_of("password");
+    let rejoin_token = matches.value_of("rejoin")
+        .map(|token| u64::from_str_radix(token, 16).expect("Unable to parse session"));
+    let default_name = if is_client { "Player 2" } else { "Player 1" };
+    let player_name = sanitize_name(matches.value_of("name").unwrap_or(default_name));
+
+    /* Both the dedicated server and a hosted game run the same server, the host joins it like any client. */
+    if is_server || matches.is_present("dedicated") {
+        let config = ServerConfig {
+            name: sanitize_name(matches.value_of("server-name").unwrap_or("Rusty Snake")),
+            game_area_height: GAME_AREA_HEIGHT,
+            game_area_width: GAME_AREA_WIDTH,
+            food_count,
+            peer_timeout: Duration::from_secs(peer_timeout),
+            grace_period: Duration::from_secs(grace_period),
+            log: !is_server,
+            lockstep: matches.is_present("lockstep"),
+            transport,
+            password: password.map(String::from),
+        };
+        let server = Server::bind(listen_address(bind, server_port), config).expect("couldn't bind to address");
+        if !is_server {
+            println!("Rusty Snake server listening on {} over {}", server.local_addr(), transport);
+            server.run();
+            return;
+        }
+        address = server.loopback_addr().to_string();
+        thread::spawn(move || server.run());
+    }
 
     /*  Set game mode based on user input. */
     if is_server {
         game.set_mode(MODE::Server);
     }
-    else if matches.is_present("connect") {
+    else if is_client {
         game.set_mode(MODE::Client);
     }
     else {
         game.set_mode(MODE::Single);
     }
 
+    /* Without an address the player picks one of the servers announcing themselves. */
+    if matches.is_present("lan") {
+        let mut browser = match Browser::bind(DISCOVERY_PORT) {
+            Ok(browser) => browser,
+            Err(error) => {
+                eprintln!("couldn't listen for servers on port {}: {}", DISCOVERY_PORT, error);
+                process::exit(1);
+            },
+        };
+        init_ncurses();
+        let found = find_game(&mut browser);
+        endwin();
+        match found {
+            Some(found) => address = found.to_string(),
+            None => return,
+        }
+    }
+
     /* Initialize networ connection. */
-    let socket = init_network(&game, &server_port, &address);
+    let client_bind = if is_server { None } else { bind };
+    let mut connection = match init_network(&game, client_bind, &address, Duration::from_secs(peer_timeout),
+                                            transport, password.map(Key::from_password)) {
+        Ok(connection) => connection,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        },
+    };
+    shutdown::notify_on_exit(connection.as_ref().and_then(Connection::farewell));
+    let mut names = match game.get_mode() {
+        MODE::Single => vec![player_name.clone(), String::from("Computer")],
+        _ => vec![player_name.clone(), String::from("Player 2")],
+    };
+    let mut settings: Option<GameSettings> = None;
+
+    /* Client joins the server before the screen is taken over, so errors stay readable. */
+    if let Some(connection) = connection.as_mut() {
+        let request = match rejoin_token {
+            Some(token) => COMMANDS::Reconnect{version: PROTOCOL_VERSION, token},
+            None => COMMANDS::Connect{version: PROTOCOL_VERSION, name: player_name.clone()},
+        };
+        match connection.join(&request) {
+            Ok(accepted) => {
+                game.set_area_size(accepted.game_area_height, accepted.game_area_width);
+                names = accepted.player_names.clone();
+                settings = Some(accepted);
+            },
+            // A private server doesn't answer anybody without the right password.
+            Err(error @ ConnectError::Timeout) if password.is_some() => {
+                eprintln!("{}, is the password right?", error);
+                process::exit(1);
+            },
+            Err(error) => {
+                eprintln!("{}", error);
+                process::exit(1);
+            },
+        }
+    }
 
     /* Initialising ncurses. */
     init_ncurses();
 
     /* Initialising game windows. */
     init_window_colors();
-    let status_window = create_status_window();
+    let status_window = create_status_window(&game);
     let game_window = create_game_area(&mut game, &mut snakes);
+    match &settings {
+        Some(accepted) => game.set_food_count(accepted.food_count as usize),
+        None => game.set_food_count(food_count.unwrap_or(snakes.len() + 1)),
+    }
 
     /* Initialising a game. */
-    let mut endgame = false;
-    let mut pressed_key = KEY_LEFT;
-    let mut received_command = KEY_LEFT;
+    let is_multiplayer = !matches!(game.get_mode(), MODE::Single);
+    let mut end_reason: Option<EndReason> = None;
+    let mut notify_peer = false;
+    let mut waiting_since: Option<Instant> = None;
+    let mut reconnect_retry = Interval::new(RECONNECT_RETRY);
     let mut direction_from_key = DIRECTION::Left;
-    let mut direction_from_udp = DIRECTION::Left;
     let mut direction_from_alg = DIRECTION::Left;
+    let session_token = settings.as_ref().map_or(0, |accepted| accepted.session_token);
+    let mut prediction: Option<Prediction> = None;
+    let mut lockstep = false;
+    let mut debug_window: Option<WINDOW> = None;
+    let mut bandwidth = Bandwidth::new();
+    let mut chat: Vec<ChatMessage> = vec![];
+    let mut chat_input: Option<TextInput> = None;
+
+    if is_multiplayer {
+        print_session(status_window, session_token);
+        wrefresh(status_window);
+    }
 
-    match game.get_mode() {
-        MODE::Server => {
-            game.init_food(&snakes[0].body, &snakes[1].body);
-            loop {
-                match socket.recv_from(&mut udp_buffer) {
-                    Ok(n) => {
-                        // n.1 -> data source address
-                        socket.connect(n.1).expect("connect function failed");
-
-                        let deserialized: COMMANDS = bincode::deserialize(&udp_buffer[0..(n.0)]).unwrap();
-                        match deserialized {
-                            COMMANDS::Connect => { },
-                            _ => {}
-                        }
-
-                        let mut snake1 = snakes[0].body.to_vec();
-                        let mut snake2 = snakes[1].body.to_vec();
-                        game.transform_coords(&mut snake1);
-                        game.transform_coords(&mut snake2);
-                        let food = game.get_food_win();
-                        let frame = UdpFrame{snake1, snake2, food: Field{y: food.0, x: food.1} };
-
-                        let serialized = bincode::serialize(&COMMANDS::ServerData(frame)).unwrap();
-                        socket.send(&serialized).expect("couldn't send message");
-
-                        break;
-                    },
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { }
-                    Err(e) =>  {
-                        println!("encountered IO error: {}", e)
-                    },
-                }
-                sleep(Duration::from_millis(10));
-            }
-        }
-        MODE::Client => {
-            loop {
-                match socket.recv_from(&mut udp_buffer) {
-                    Ok(n) => {
-                        let deserialized: COMMANDS = bincode::deserialize(&udp_buffer[0..(n.0)]).unwrap();
-                        match deserialized {
-                            COMMANDS::ServerData(frame) => {
-                                game.set_food(frame.food.y, frame.food.x);
-                                update_score(status_window, &socket, &game.get_mode(),
-                                             &(frame.snake1.len()).try_into().unwrap(),
-                                             &(frame.snake2.len()).try_into().unwrap());
-                                game.draw_snake(frame.snake1, frame.snake2);
-                            }
-                            _ => {}
-                        }
-                        break;
-                    },
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { }
-                    Err(e) => {
-                        println!("encountered IO error: {}", e)
-                    },
-                }
+    /* Before the match the client picks a room in the lobby and waits there for the countdown. */
+    match (connection.as_mut(), settings.as_mut()) {
+        (Some(connection), Some(accepted)) => {
+            let joined = match accepted.room {
+                Some(_) => Ok(()),
+                None => choose_room(connection, game_window, accepted),
+            };
+            match joined.and_then(|()| wait_in_room(connection, game_window, accepted)) {
+                Ok(frame) => {
+                    names = accepted.player_names.clone();
+                    set_player_colors(&accepted.player_colors);
+                    clear_game_area(game_window);
+                    match (accepted.spectator, accepted.lockstep) {
+                        (true, _) => game.set_mode(MODE::Spectator),
+                        // Coming back into a running match there is no game of our own to simulate.
+                        (false, Some(seed)) if frame.tick == 0 => {
+                            game.restart(&snakes, seed);
+                            lockstep = true;
+                        },
+                        (false, _) => prediction = Some(Prediction::new(VecDeque::new())),
+                    }
+                    frame_filter.accept(frame.tick, &mut connection.stats);
+                    connection.stats.keyframes += 1;
+                    connection.send(&COMMANDS::FrameAck(frame.tick));
+                    frames.push(frame.clone());
+                    update_score(status_window, Some(connection), &game.get_mode(), &names,
+                                 &(frame.snake1.len()).try_into().unwrap(),
+                                 &(frame.snake2.len()).try_into().unwrap());
+                    if !lockstep {
+                        show_frame(&mut game, prediction.as_mut(), accepted.player, frame);
+                    }
+                },
+                // Leaving before the match, there is no result to show.
+                Err(EndReason::GameOver) => {
+                    connection.send(&COMMANDS::Endgame);
+                    endwin();
+                    return;
+                },
+                Err(reason) => end_reason = Some(reason),
             }
         }
-        MODE::Single => {
-            game.init_food(&snakes[0].body, &snakes[1].body);
+        _ => {
+            game.init_food();
         }
     }
 
+    let player_slot = settings.as_ref().map_or(0, |accepted| accepted.player);
+    // Only once in the match, the lobby and the room have no chat.
+    let chat_window = if is_multiplayer { create_chat_window(&game) } else { None };
+    let mut latency = Latency::new();
+    let mut tick_rate = TickRate::new();
+
     loop
     {
         let now = Instant::now();
+        let mut turn_key = -1;
 
         /* Loop for checking inputs and timeout. */
-        while (now.elapsed().as_millis() as i32) < REFRESH_TIMEOUT {
-
-            pressed_key = getch();
+        while end_reason.is_none() && (now.elapsed().as_millis() as i32) < game.get_refresh_timeout() {
+
+            let mut pressed_key = getch();
+            /* While chatting every key but the arrows goes into the line being typed. */
+            if pressed_key != -1 && !DIRECTIONS.contains_key(&pressed_key) {
+                if let Some(input) = chat_input.as_mut() {
+                    match input.handle_key(pressed_key) {
+                        InputEvent::Editing => {},
+                        InputEvent::Submitted(text) => {
+                            if let Some(connection) = connection.as_mut() {
+                                connection.send(&COMMANDS::Chat(ChatMessage{from: String::new(), text}));
+                            }
+                            chat_input = None;
+                        },
+                        InputEvent::Cancelled => chat_input = None,
+                    }
+                    if let Some(win) = chat_window {
+                        print_chat(win, &chat, chat_input.as_ref());
+                    }
+                    pressed_key = -1;
+                }
+            }
             if pressed_key != -1 {
                 if let Some(direction) = DIRECTIONS.get(&pressed_key) {
                     direction_from_key = direction.clone();
-                    break;
+                    turn_key = pressed_key;
+                    // A predicting client keeps its ticks as regular as the server's steps.
+                    if prediction.is_none() {
+                        break;
+                    }
                 }
             }
 
-            match socket.recv_from(&mut udp_buffer) {
-                Ok(n) => {
-                    let deserialized: COMMANDS = bincode::deserialize(&udp_buffer[0..(n.0)]).unwrap();
-                    match deserialized {
-                        COMMANDS::Key(key) => {
-                            received_command = key;
-                        },
-                        COMMANDS::ServerData(frame) => {
-                            game.set_food(frame.food.y, frame.food.x);
-                            update_score(status_window, &socket, &game.get_mode(),
-                                         &(frame.snake1.len()).try_into().unwrap(),
-                                         &(frame.snake2.len()).try_into().unwrap());
-                            game.draw_snake(frame.snake1, frame.snake2);
+            if let Some(connection) = connection.as_mut() {
+                let mut received: Option<UdpFrame> = None;
+                match connection.receive(&mut udp_buffer) {
+                    Ok(Some((COMMANDS::ServerData(frame), _))) => {
+                        connection.stats.keyframes += 1;
+                        received = Some(frame);
+                    }
+                    Ok(Some((COMMANDS::ServerDelta(delta), _))) => {
+                        // Without its baseline a delta is of no use, the next full frame catches up.
+                        connection.stats.deltas += 1;
+                        received = frames.apply(delta);
+                    }
+                    Ok(Some((COMMANDS::Inputs(batch), _))) if lockstep => {
+                        // Ticks simulated already are in the batch again until we acknowledge them.
+                        for (tick, directions) in (batch.first..).zip(&batch.directions) {
+                            if tick == game.get_tick() + 1 {
+                                game.step(&mut snakes, directions);
+                                if tick % CHECKSUM_INTERVAL == 0 {
+                                    connection.send(&COMMANDS::Checksum{tick, checksum: game.checksum(&snakes)});
+                                }
+                            }
+                        }
+                        connection.send(&COMMANDS::FrameAck(game.get_tick()));
+                        update_score(status_window, Some(connection), &game.get_mode(), &names,
+                                     &(snakes[0].body().len()).try_into().unwrap(),
+                                     &(snakes[1].body().len()).try_into().unwrap());
+                    }
+                    Ok(Some((COMMANDS::Endgame, _))) => {
+                        connection.send(&COMMANDS::EndgameAck);
+                        end_reason = Some(EndReason::GameOver);
+                    }
+                    Ok(Some((COMMANDS::Accept(accepted), _))) => {
+                        // The server took us back after a reconnect.
+                        names = accepted.player_names;
+                    }
+                    Ok(Some((COMMANDS::Reject(_), _))) => {
+                        // The server no longer knows our session, there is nothing to come back to.
+                        end_reason = Some(EndReason::Disconnected);
+                    }
+                    Ok(Some((COMMANDS::Pong(seq), _))) => latency.pong(seq),
+                    Ok(Some((COMMANDS::Chat(message), _))) => {
+                        chat.push(message);
+                        if chat.len() > CHAT_HISTORY {
+                            chat.remove(0);
                         }
-                        COMMANDS::Endgame => {
-                            endgame = true;
+                        if let Some(win) = chat_window {
+                            print_chat(win, &chat, chat_input.as_ref());
                         }
-                        _ => {println!("encountered IO error: ")}
                     }
-                },
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
-                Err(e) => {
-                    mvwaddstr(stdscr(), LINES() - 22, 0, "BLAD POLACZENIA");
-                    println!("{}", e);
-                },
-            }
+                    // Heartbeats and dropped packets end up here.
+                    Ok(_) => {},
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    // Counted, the status window shows how many there were.
+                    Err(_) => connection.stats.io_errors += 1,
+                }
+
+                if let Some(frame) = received {
+                    // Older frames than the one on screen would make the snakes jump back.
+                    if frame_filter.accept(frame.tick, &mut connection.stats) {
+                        // The server stopped sending inputs, its frames take over from our own game.
+                        if lockstep {
+                            lockstep = false;
+                            game.foods.clear();
+                            clear_game_area(game_window);
+                        }
+                        // The server builds the next deltas on the newest frame we have.
+                        connection.send(&COMMANDS::FrameAck(frame.tick));
+                        frames.push(frame.clone());
+                        update_score(status_window, Some(connection), &game.get_mode(), &names,
+                                     &(frame.snake1.len()).try_into().unwrap(),
+                                     &(frame.snake2.len()).try_into().unwrap());
+                        show_frame(&mut game, prediction.as_mut(), player_slot, frame);
+                    }
+                }
 
-            if let Some(direction) = DIRECTIONS.get(&received_command) {
-                direction_from_udp = direction.clone();
+                /* A silent peer gets a grace period to come back before the match is lost. */
+                connection.keep_alive();
+                latency.ping(connection);
+                match waiting_since {
+                    None if connection.heartbeat.timed_out() => {
+                        waiting_since = Some(Instant::now());
+                        print_status_message(status_window, "RECONNECTING");
+                        wrefresh(status_window);
+                    },
+                    Some(_) if !connection.heartbeat.timed_out() => {
+                        waiting_since = None;
+                        print_status_message(status_window, "");
+                        wrefresh(status_window);
+                    },
+                    Some(since) if since.elapsed() >= Duration::from_secs(grace_period) => {
+                        end_reason = Some(EndReason::Disconnected);
+                    },
+                    _ => {},
+                }
+                if waiting_since.is_some() && reconnect_retry.ready() {
+                    connection.send(&COMMANDS::Reconnect{version: PROTOCOL_VERSION, token: session_token});
+                }
             }
 
             let (head_y, head_x) = snakes[1].get_head();
-            let (target_y, target_x) = game.get_food();
+            let (target_y, target_x) = game.get_nearest_food(&head_y, &head_x);
             direction_from_alg = calculate_direction(&snakes[1], &target_y, &target_x, &head_y, &head_x);
 
             if pressed_key == 'q' as i32 {
-                endgame = true;
+                end_reason = Some(EndReason::GameOver);
+                notify_peer = is_multiplayer;
                 break;
             };
+            if pressed_key == 'd' as i32 && is_multiplayer {
+                debug_window = match debug_window.take() {
+                    Some(win) => {
+                        destroy_window(win);
+                        None
+                    },
+                    None => Some(create_debug_window()),
+                };
+            }
+            if let (true, Some(win)) = (pressed_key == 't' as i32, chat_window) {
+                chat_input = Some(TextInput::new(MAX_CHAT_LENGTH));
+                print_chat(win, &chat, chat_input.as_ref());
+            }
+            shutdown::exit_if_requested();
             sleep(Duration::from_millis(10));
         }
 
         /* Precess inputs from key, network. */
-        match game.get_mode() {
-            MODE::Client => {
-                let serialized = bincode::serialize(&COMMANDS::Key(pressed_key)).unwrap();
-                socket.send(&serialized).expect("couldn't send message");
-            }
-            MODE::Server => {
-                if let Some(snake) = snakes.get_mut(0) {
-                    snake.change_direction(&direction_from_key)
-                }
-                if let Some(snake) = snakes.get_mut(1) {
-                    snake.change_direction(&direction_from_udp)
+        match connection.as_mut() {
+            // Spectators only watch.
+            Some(_) if settings.as_ref().is_some_and(|accepted| accepted.spectator) => {},
+            Some(connection) => {
+                input_seq += 1;
+                connection.send(&COMMANDS::Key{seq: input_seq, key: turn_key});
+                // The own snake moves right away, the server's frame corrects it later.
+                if let Some(prediction) = prediction.as_mut() {
+                    prediction.step(input_seq, DIRECTIONS.get(&turn_key).cloned());
+                    let (snake1, snake2) = (game.clear1_buffer.clone(), game.clear2_buffer.clone());
+                    draw_snakes(&mut game, prediction, player_slot, snake1, snake2);
                 }
-
-                let mut snake1 = snakes[0].body.to_vec();
-                let mut snake2 = snakes[1].body.to_vec();
-                game.transform_coords(&mut snake1);
-                game.transform_coords(&mut snake2);
-                let food = game.get_food_win();
-                let frame = UdpFrame{snake1, snake2, food: Field{y: food.0, x: food.1} };
-
-                let serialized = bincode::serialize(&COMMANDS::ServerData(frame)).unwrap();
-                socket.send(&serialized).expect("couldn't send message");
-            },
-            MODE::Single => {
+            }
+            None => {
                 if let Some(snake) = snakes.get_mut(0) {
                     snake.change_direction(&direction_from_key)
                 }
@@ -263,91 +542,332 @@ fn main() {
             },
         }
 
-        /* Snake moves in Single player and Server mode. */
-        match game.get_mode() {
-            MODE::Client => {},
-            _ => {
-                for mut snake in &mut snakes {
-                    snake.move_snake();
-                    game.check_food(&mut snake);
+        /* Snakes move locally only in Single player, otherwise the server moves them. */
+        if let MODE::Single = game.get_mode() {
+            game.move_snakes(&mut snakes);
+            game.check_food(&mut snakes);
+            game.update_food();
+            if end_reason.is_none() {
+                if game.is_board_full() {
+                    end_reason = Some(EndReason::BoardFull);
                 }
-                if game.check_collisions(&snakes) {
-                    endgame = true;
+                else if game.check_collisions(&snakes) {
+                    end_reason = Some(EndReason::GameOver);
                 }
-
-                update_score(status_window, &socket, &game.get_mode(),
-                             &(snakes[0].body.len()).try_into().unwrap(),
-                             &(snakes[1].body.len()).try_into().unwrap());
             }
+
+            update_score(status_window, None, &game.get_mode(), &names,
+                         &(snakes[0].body().len()).try_into().unwrap(),
+                         &(snakes[1].body().len()).try_into().unwrap());
         }
 
         /* End game and print information to user. */
-        if endgame {
+        if let Some(reason) = &end_reason {
             werase(stdscr());
             refresh();
 
             destroy_window(game_window);
             destroy_window(status_window);
+            if let Some(win) = debug_window.take() {
+                destroy_window(win);
+            }
+            if let Some(win) = chat_window {
+                destroy_window(win);
+            }
             let win = create_ending_window();
 
             match game.get_mode() {
                 MODE::Single => {
-                    let score1: &i32 = &(snakes[0].body.len()).try_into().unwrap();
-                    let score2: &i32 = &(snakes[1].body.len()).try_into().unwrap();
-                    print_ending_info(win, &score1, &score2);
+                    let score1: &i32 = &(snakes[0].body().len()).try_into().unwrap();
+                    let score2: &i32 = &(snakes[1].body().len()).try_into().unwrap();
+                    print_ending_info(win, score1, score2, reason);
                 },
-                MODE::Server => {
-                    send_endgame(&socket);
-
-                    let score1: &i32 = &(snakes[0].body.len()).try_into().unwrap();
-                    let score2: &i32 = &(snakes[1].body.len()).try_into().unwrap();
-                    print_ending_info(win, &score1, &score2);
+                MODE::Spectator => {
+                    let score1: &i32 = &(game.clear1_buffer.len()).try_into().unwrap();
+                    let score2: &i32 = &(game.clear2_buffer.len()).try_into().unwrap();
+                    print_match_result(win, &names, score1, score2, reason);
                 },
-                MODE::Client => {
-                    let score1: &i32 = &(game.clear2_buffer.len()).try_into().unwrap();
-                    let score2: &i32 = &(game.clear1_buffer.len()).try_into().unwrap();
-                    print_ending_info(win, &score1, &score2);
+                _ => {
+                    /* Own score first, the server tells which snake is ours. */
+                    let (snake1, snake2) = match lockstep {
+                        true => (snakes[0].body(), snakes[1].body()),
+                        false => (&game.clear1_buffer, &game.clear2_buffer),
+                    };
+                    let (own, other) = match player_slot {
+                        0 => (snake1, snake2),
+                        _ => (snake2, snake1),
+                    };
+                    let score1: &i32 = &(own.len()).try_into().unwrap();
+                    let score2: &i32 = &(other.len()).try_into().unwrap();
+                    print_ending_info(win, score1, score2, reason);
                 },
             }
 
+            /* Keep the peer informed while the result is on screen. */
+            let mut notice = EndgameNotice::new(notify_peer);
             while getch() != 'q' as i32 {
+                if let Some(connection) = connection.as_mut() {
+                    notice.resend(connection);
+                    match connection.receive(&mut udp_buffer) {
+                        Ok(Some((COMMANDS::Endgame, _))) => connection.send(&COMMANDS::EndgameAck),
+                        Ok(Some((COMMANDS::EndgameAck, _))) => notice.acknowledge(),
+                        _ => {},
+                    }
+                }
+                shutdown::exit_if_requested();
                 sleep(Duration::from_millis(10));
             }
             destroy_window(win);
             break;
         }
 
+        if let Some(connection) = &connection {
+            tick_rate.update(if lockstep { game.get_tick() } else { frame_filter.last() });
+            print_latency(status_window, &latency, &tick_rate);
+            update_packet_stats(status_window, &connection.stats);
+            bandwidth.update(&connection.stats);
+            if let Some(win) = debug_window {
+                print_debug_overlay(win, &connection.stats, &bandwidth);
+            }
+        }
         wrefresh(status_window);
         wrefresh(game_window);
     }
     endwin();
 }
 
-fn calculate_direction(snake: &Snake, target_y: &i32, target_x: &i32, head_y: &i32, head_x: &i32) -> DIRECTION {
-    let mut direction = DIRECTION::Left;
-    let mut helper_flag = false;
+/* Makes clap reject a value that doesn't parse, with a usage error instead of a panic later on. */
+fn parses<T: FromStr>(what: &'static str) -> impl Fn(String) -> Result<(), String> {
+    move |value| match value.parse::<T>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("'{}' is not a valid {}", value, what)),
+    }
+}
+
+fn is_session(value: String) -> Result<(), String> {
+    match u64::from_str_radix(&value, 16) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("'{}' is not a session, it is the hex number shown in the status window", value)),
+    }
+}
 
-    if head_x > target_x {
-        direction = DIRECTION::Left;
-        if snake.direction == DIRECTION::Right {
-            helper_flag = true;
-        };
+fn is_server_address(value: String) -> Result<(), String> {
+    match value.to_socket_addrs().map(|mut addresses| addresses.next()) {
+        Ok(Some(_)) => Ok(()),
+        _ => Err(format!("'{}' is not a server address, it looks like 192.168.0.1:10000", value)),
     }
-    if head_x < target_x {
-        direction = DIRECTION::Right;
-        if snake.direction == DIRECTION::Left {
-            helper_flag = true;
-        };
+}
+
+fn is_bind_address(value: String) -> Result<(), String> {
+    match (value.parse::<SocketAddr>(), value.parse::<IpAddr>()) {
+        (Err(_), Err(_)) => Err(format!("'{}' is not an address with or without a port", value)),
+        _ => Ok(()),
     }
-    if head_x == target_x || helper_flag == true {
-        if head_y < target_y {
-            direction = DIRECTION::Down;
-        };
-        if head_y > target_y {
-            direction = DIRECTION::Up;
-        };
+}
+
+/* Draws a frame from the server, with the own snake where the prediction has it. */
+fn show_frame(game: &mut Game, prediction: Option<&mut Prediction>, slot: u32, frame: UdpFrame) {
+    game.set_foods(frame.foods);
+    game.set_refresh_timeout(frame.refresh_timeout);
+    match prediction {
+        Some(prediction) => {
+            let own = match slot {
+                0 => frame.snake1.clone(),
+                _ => frame.snake2.clone(),
+            };
+            prediction.reconcile(own, frame.inputs.get(slot as usize).copied().unwrap_or(0));
+            draw_snakes(game, prediction, slot, frame.snake1, frame.snake2);
+        },
+        None => game.draw_snake(frame.snake1, frame.snake2),
+    }
+}
+
+fn draw_snakes(game: &mut Game, prediction: &Prediction, slot: u32,
+               mut snake1: VecDeque<Field>, mut snake2: VecDeque<Field>) {
+    match slot {
+        0 => snake1 = prediction.body().clone(),
+        _ => snake2 = prediction.body().clone(),
+    }
+    game.draw_snake(snake1, snake2);
+}
+
+/* Find games: lists the servers on the local network until the player picks one. */
+fn find_game(browser: &mut Browser) -> Option<SocketAddr> {
+    let mut selected: usize = 0;
+    let mut redraw = true;
+
+    loop {
+        let key = getch();
+        if key != -1 {
+            redraw = true;
+        }
+        match key {
+            KEY_UP => selected = selected.saturating_sub(1),
+            KEY_DOWN => selected += 1,
+            10 | 13 | KEY_ENTER => {
+                if let Some(server) = browser.servers.get(selected) {
+                    return Some(server.address);
+                }
+            },
+            key if key == 'q' as i32 => return None,
+            _ => {},
+        }
+
+        redraw |= browser.poll();
+        selected = selected.min(browser.servers.len().saturating_sub(1));
+        if redraw {
+            print_servers(stdscr(), &browser.servers, selected);
+            redraw = false;
+        }
+        shutdown::exit_if_requested();
+        sleep(Duration::from_millis(10));
+    }
+}
+
+/* Lobby: lists the rooms until the player opens a new one or gets into an existing one. */
+fn choose_room(connection: &mut Connection, win: WINDOW, settings: &mut GameSettings) -> Result<(), EndReason> {
+    let mut udp_buffer = [0; 10000];
+    let mut rooms: Vec<RoomInfo> = vec![];
+    let mut selected: usize = 0;
+    let mut new_room: Option<TextInput> = None;
+    let mut message = String::new();
+    let mut request: Option<COMMANDS> = None;
+    let mut retry = Interval::new(RECONNECT_RETRY);
+    let mut redraw = true;
+
+    loop {
+        let key = getch();
+        if key != -1 {
+            redraw = true;
+        }
+        match new_room.as_mut().map(|input| input.handle_key(key)) {
+            Some(InputEvent::Submitted(name)) => {
+                request = Some(COMMANDS::CreateRoom(name));
+                new_room = None;
+            },
+            Some(InputEvent::Cancelled) => new_room = None,
+            Some(InputEvent::Editing) => {},
+            None => match key {
+                KEY_UP => selected = selected.saturating_sub(1),
+                KEY_DOWN => selected = (selected + 1).min(rooms.len().saturating_sub(1)),
+                10 | 13 | KEY_ENTER => {
+                    if let Some(room) = rooms.get(selected) {
+                        request = Some(COMMANDS::JoinRoom(room.id));
+                    }
+                },
+                key if key == 'w' as i32 => {
+                    if let Some(room) = rooms.get(selected) {
+                        request = Some(COMMANDS::WatchRoom(room.id));
+                    }
+                },
+                key if key == 'n' as i32 => new_room = Some(TextInput::new(MAX_NAME_LENGTH)),
+                key if key == 'q' as i32 => return Err(EndReason::GameOver),
+                _ => {},
+            },
+        }
+
+        match connection.receive(&mut udp_buffer) {
+            Ok(Some((COMMANDS::RoomList(list), _))) => {
+                rooms = list;
+                selected = selected.min(rooms.len().saturating_sub(1));
+                redraw = true;
+            },
+            Ok(Some((COMMANDS::Accept(accepted), _))) if accepted.room.is_some() => {
+                *settings = accepted;
+                return Ok(());
+            },
+            Ok(Some((COMMANDS::Reject(reason), _))) => {
+                message = reason;
+                request = None;
+                redraw = true;
+            },
+            Ok(_) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            Err(_) => connection.stats.io_errors += 1,
+        }
+
+        /* The request is repeated until the server answers, it may get lost on the way. */
+        if let Some(request) = &request {
+            if retry.ready() {
+                connection.send(request);
+            }
+        }
+        connection.keep_alive();
+        if connection.heartbeat.timed_out() {
+            return Err(EndReason::Disconnected);
+        }
+
+        if redraw {
+            print_lobby(win, &rooms, selected, new_room.as_ref(), &message);
+            redraw = false;
+        }
+        shutdown::exit_if_requested();
+        sleep(Duration::from_millis(10));
+    }
+}
+
+/* Room: players get ready and pick colors until the host's countdown runs out and the first frame arrives.
+ * Spectators only wait, or get the frame of a running match right away. */
+fn wait_in_room(connection: &mut Connection, win: WINDOW, settings: &mut GameSettings) -> Result<UdpFrame, EndReason> {
+    let mut udp_buffer = [0; 10000];
+    let mut status: Option<RoomStatus> = None;
+    let mut ready = false;
+    let mut retry = Interval::new(RECONNECT_RETRY);
+
+    loop {
+        let me = if settings.spectator { None } else { Some(settings.player as usize) };
+        let mine = status.as_ref().zip(me).and_then(|(status, me)| status.players.get(me));
+
+        match getch() {
+            key if key == 'q' as i32 => return Err(EndReason::GameOver),
+            _ if me.is_none() => {},
+            key if key == 'r' as i32 => {
+                ready = !ready;
+                connection.send(&COMMANDS::Ready(ready));
+            },
+            key if key == 'c' as i32 => {
+                if let (Some(status), Some(mine)) = (&status, mine) {
+                    // Next color nobody else in the room has.
+                    let free = (1..PLAYER_COLORS)
+                        .map(|step| (mine.color + step) % PLAYER_COLORS)
+                        .find(|color| status.players.iter().all(|player| player.color != *color));
+                    if let Some(color) = free {
+                        connection.send(&COMMANDS::Color(color));
+                    }
+                }
+            },
+            key if key == 's' as i32 => connection.send(&COMMANDS::StartMatch),
+            _ => {},
+        }
+
+        // A lost ready message would leave the room waiting for us.
+        if mine.is_some_and(|mine| mine.ready != ready) && retry.ready() {
+            connection.send(&COMMANDS::Ready(ready));
+        }
+
+        match connection.receive(&mut udp_buffer) {
+            Ok(Some((COMMANDS::RoomUpdate(update), _))) => {
+                print_room(win, &update, me);
+                status = Some(update);
+            },
+            Ok(Some((COMMANDS::Accept(accepted), _))) => *settings = accepted,
+            Ok(Some((COMMANDS::ServerData(frame), _))) => return Ok(frame),
+            Ok(Some((COMMANDS::Endgame, _))) => {
+                connection.send(&COMMANDS::EndgameAck);
+                return Err(EndReason::Disconnected);
+            },
+            Ok(_) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            Err(_) => connection.stats.io_errors += 1,
+        }
+
+        connection.keep_alive();
+        if connection.heartbeat.timed_out() {
+            return Err(EndReason::Disconnected);
+        }
+        shutdown::exit_if_requested();
+        sleep(Duration::from_millis(10));
     }
-    direction
 }
 
 fn init_ncurses() {
@@ -364,18 +884,3 @@ fn init_ncurses() {
     mvwaddstr(stdscr(), LINES() - 1, 0, "Press 'q' to exit");
     start_color();
 }
-
-fn print_block(y: &i32, x: &i32, color: &i16) {
-    attr_on(COLOR_PAIR(*color));
-    mvaddch(*y, *x, ACS_CKBOARD());
-}
-
-fn print_food(y: &i32, x: &i32) {
-    attr_on(COLOR_PAIR(COLOR_PAIR_FOOD));
-    mvaddch(*y, *x, ACS_DIAMOND());
-}
-
-fn print_space(y: &i32, x: &i32) {
-    let ch = ' ' as chtype;
-    mvaddch(*y, *x, ch);
-}
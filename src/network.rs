@@ -1,46 +1,658 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-use serde::{Deserialize, Serialize};
+use crate::auth::{Authenticated, Key};
+use crate::game::{Game, MODE};
+use crate::protocol::{decode, encode_datagrams, Fragment, GameSettings, ProtocolError, COMMANDS,
+                      MAX_FRAGMENTS, PROTOCOL_VERSION};
+use crate::shutdown;
+use crate::transport::{Transport, TransportKind};
 
-use crate::COMMANDS;
-use crate::game::{Field, Game, MODE};
+static HANDSHAKE_TIMEOUT: u64 = 5000;
+static HANDSHAKE_RETRY: u64 = 500;
+static HEARTBEAT_INTERVAL: u64 = 1000;
+static ENDGAME_RESEND: u64 = 200;
+pub static RECONNECT_RETRY: u64 = 500;
+pub static MAX_NAME_LENGTH: usize = 16;
+pub static MAX_CHAT_LENGTH: usize = 60;
+static PING_INTERVAL: u64 = 500;
+/* A ping not answered in this time counts as lost. */
+static PING_TIMEOUT: Duration = Duration::from_secs(2);
+/* Loss is worked out over this many of the last pings. */
+static PING_WINDOW: usize = 20;
+/* Messages being put back together at once, an older one is given up. */
+static PARTIAL_MESSAGES: usize = 4;
 
-#[derive(Serialize, Deserialize)]
-pub struct UdpFrame {
-    pub(crate) snake1: Vec<Field>,
-    pub(crate) snake2: Vec<Field>,
-    pub(crate) food: Field,
+/* Counters for everything the receive path had to throw away. */
+#[derive(Default)]
+pub struct PacketStats {
+    pub received: u64,
+    pub malformed: u64,
+    pub unknown_peer: u64,
+    pub stale: u64,
+    pub io_errors: u64,
+    /* Sizes of what came in, for the debug overlay. */
+    pub bytes: u64,
+    pub last_size: usize,
+    pub largest: usize,
+    pub fragments: u64,
+    pub keyframes: u64,
+    pub deltas: u64,
 }
 
-pub fn init_network (game: &Game, port: &str, address: &str) -> UdpSocket {
-    let socket: UdpSocket;
+impl PacketStats {
+    pub fn dropped(&self) -> u64 {
+        self.malformed + self.unknown_peer + self.stale
+    }
+}
+
+/* Lets through only sequence numbers newer than the last accepted one,
+ * so reordered and duplicated datagrams are ignored. */
+#[derive(Default)]
+pub struct SequenceFilter {
+    last: Option<u64>,
+}
 
-    match game.get_mode() {
-        MODE::Server => {
-            socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).expect("couldn't bind to address");
+impl SequenceFilter {
+    pub fn accept(&mut self, seq: u64, stats: &mut PacketStats) -> bool {
+        if self.last.is_some_and(|last| seq <= last) {
+            stats.stale += 1;
+            return false;
         }
-        MODE::Client => {
-            socket = UdpSocket::bind("0.0.0.0:10000").expect("couldn't bind to address");
-            let target_server_address: SocketAddr = address.parse().expect("Unable to parse socket address");
-            socket.connect(target_server_address).expect("connect function failed");
+        self.last = Some(seq);
+        true
+    }
 
-            // send any msg to server to connect
-            send_connect(&socket);
+    pub fn last(&self) -> u64 {
+        self.last.unwrap_or(0)
+    }
+}
+
+/* What came in per second, measured over about a second. */
+pub struct Bandwidth {
+    since: Instant,
+    bytes: u64,
+    packets: u64,
+    pub bytes_per_second: u64,
+    pub packets_per_second: u64,
+}
+
+impl Bandwidth {
+    pub fn new() -> Self {
+        Bandwidth { since: Instant::now(), bytes: 0, packets: 0, bytes_per_second: 0, packets_per_second: 0 }
+    }
+
+    pub fn update(&mut self, stats: &PacketStats) {
+        let elapsed = self.since.elapsed().as_secs_f64();
+        if elapsed < 1.0 {
+            return;
         }
-        MODE::Single => {
-            socket = UdpSocket::bind("127.0.0.1:10000").expect("couldn't bind to address");
+        self.bytes_per_second = ((stats.bytes - self.bytes) as f64 / elapsed) as u64;
+        self.packets_per_second = ((stats.received - self.packets) as f64 / elapsed) as u64;
+        self.bytes = stats.bytes;
+        self.packets = stats.received;
+        self.since = Instant::now();
+    }
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Bandwidth::new()
+    }
+}
+
+/* Ticks of the match per second, measured over about a second. */
+pub struct TickRate {
+    since: Instant,
+    tick: u64,
+    pub ticks_per_second: f64,
+}
+
+impl TickRate {
+    pub fn new() -> Self {
+        TickRate { since: Instant::now(), tick: 0, ticks_per_second: 0.0 }
+    }
+
+    /* Takes the newest tick seen, a tick from before a restart starts over. */
+    pub fn update(&mut self, tick: u64) {
+        let elapsed = self.since.elapsed().as_secs_f64();
+        if elapsed < 1.0 {
+            return;
         }
+        self.ticks_per_second = tick.saturating_sub(self.tick) as f64 / elapsed;
+        self.tick = tick;
+        self.since = Instant::now();
+    }
+}
+
+impl Default for TickRate {
+    fn default() -> Self {
+        TickRate::new()
     }
-    socket.set_nonblocking(true).unwrap();
-    socket
 }
 
-pub fn send_connect(socket: &UdpSocket) {
-    let serialized = bincode::serialize(&COMMANDS::Connect).unwrap();
-    socket.send(&serialized).expect("couldn't send message");
+/* Round trip time to the peer from pings it answers, how much it varies and how many pings got lost. */
+pub struct Latency {
+    interval: Interval,
+    next: u64,
+    pending: VecDeque<(u64, Instant)>,
+    /* Whether each of the last pings came back. */
+    outcomes: VecDeque<bool>,
+    last: Option<Duration>,
+    /* Smoothed the way TCP does, so a single slow ping doesn't make it jump. */
+    pub rtt: Option<Duration>,
+    /* The mean difference between one round trip and the next, as in RTP. */
+    pub jitter: Duration,
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        Latency {
+            interval: Interval::new(PING_INTERVAL),
+            next: 0,
+            pending: VecDeque::new(),
+            outcomes: VecDeque::new(),
+            last: None,
+            rtt: None,
+            jitter: Duration::ZERO,
+        }
+    }
+
+    /* Sends a ping once per interval and gives up on the ones that took too long. */
+    pub fn ping(&mut self, connection: &mut Connection) {
+        while self.pending.front().is_some_and(|(_, sent)| sent.elapsed() >= PING_TIMEOUT) {
+            self.pending.pop_front();
+            self.record(false);
+        }
+        if self.interval.ready() {
+            self.next += 1;
+            self.pending.push_back((self.next, Instant::now()));
+            connection.send(&COMMANDS::Ping(self.next));
+        }
+    }
+
+    /* Late and duplicated answers are no longer pending and change nothing. */
+    pub fn pong(&mut self, seq: u64) {
+        let position = match self.pending.iter().position(|(pending, _)| *pending == seq) {
+            Some(position) => position,
+            None => return,
+        };
+        let (_, sent) = self.pending.remove(position).unwrap();
+        let sample = sent.elapsed();
+        if let Some(last) = self.last {
+            let difference = if sample > last { sample - last } else { last - sample };
+            self.jitter = (self.jitter * 15 + difference) / 16;
+        }
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+        self.last = Some(sample);
+        self.record(true);
+    }
+
+    fn record(&mut self, answered: bool) {
+        self.outcomes.push_back(answered);
+        if self.outcomes.len() > PING_WINDOW {
+            self.outcomes.pop_front();
+        }
+    }
+
+    /* Percentage of the last pings that never came back, none before the first one is decided. */
+    pub fn loss(&self) -> Option<f64> {
+        if self.outcomes.is_empty() {
+            return None;
+        }
+        let lost = self.outcomes.iter().filter(|answered| !**answered).count();
+        Some(lost as f64 * 100.0 / self.outcomes.len() as f64)
+    }
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency::new()
+    }
+}
+
+/* Puts fragmented messages back together. */
+#[derive(Default)]
+pub struct Reassembly {
+    messages: VecDeque<(u32, Vec<Option<Vec<u8>>>)>,
+}
+
+impl Reassembly {
+    /* The whole packet once the last missing fragment arrives. */
+    pub fn add(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
+        if fragment.count == 0 || fragment.count > MAX_FRAGMENTS || fragment.index >= fragment.count {
+            return None;
+        }
+        let position = match self.messages.iter().position(|(message, _)| *message == fragment.message) {
+            Some(position) => position,
+            None => {
+                if self.messages.len() == PARTIAL_MESSAGES {
+                    self.messages.pop_front();
+                }
+                self.messages.push_back((fragment.message, vec![None; fragment.count as usize]));
+                self.messages.len() - 1
+            },
+        };
+
+        let parts = &mut self.messages[position].1;
+        if parts.len() != fragment.count as usize {
+            return None;
+        }
+        parts[fragment.index as usize] = Some(fragment.data);
+        if parts.iter().any(|part| part.is_none()) {
+            return None;
+        }
+        let (_, parts) = self.messages.remove(position)?;
+        Some(parts.into_iter().flatten().flatten().collect())
+    }
+}
+
+/* Fires at most once per period, the first time right away. */
+pub struct Interval {
+    period: Duration,
+    last: Option<Instant>,
+}
+
+impl Interval {
+    pub fn new(millis: u64) -> Self {
+        Interval { period: Duration::from_millis(millis), last: None }
+    }
+
+    pub fn ready(&mut self) -> bool {
+        if self.last.is_some_and(|last| last.elapsed() < self.period) {
+            return false;
+        }
+        self.last = Some(Instant::now());
+        true
+    }
+}
+
+/* Lets a burst of events through, then one per period. */
+pub struct RateLimit {
+    burst: u32,
+    period: Duration,
+    tokens: u32,
+    last: Instant,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, millis: u64) -> Self {
+        RateLimit { burst, period: Duration::from_millis(millis), tokens: burst, last: Instant::now() }
+    }
+
+    pub fn allow(&mut self) -> bool {
+        let periods = (self.last.elapsed().as_millis() / self.period.as_millis()) as u32;
+        if periods > 0 {
+            self.tokens = self.tokens.saturating_add(periods).min(self.burst);
+            self.last = Instant::now();
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+/* Notices when the peer stops talking to us. */
+pub struct Heartbeat {
+    timeout: Duration,
+    last_heard: Instant,
+    interval: Interval,
+}
+
+impl Heartbeat {
+    pub fn new(timeout: Duration) -> Self {
+        Heartbeat {
+            timeout,
+            last_heard: Instant::now(),
+            interval: Interval::new(HEARTBEAT_INTERVAL),
+        }
+    }
+
+    /* Any datagram from the peer proves it is still there. */
+    pub fn heard(&mut self) {
+        self.last_heard = Instant::now();
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.last_heard.elapsed() >= self.timeout
+    }
+
+    /* True once per interval, when it is time to send our own heartbeat. */
+    pub fn due(&mut self) -> bool {
+        self.interval.ready()
+    }
 }
 
-pub fn send_endgame(socket: &UdpSocket) {
-    let serialized = bincode::serialize(&COMMANDS::Endgame).unwrap();
-    socket.send(&serialized).expect("couldn't send message");
+/* A transport together with the single peer we play with.
+ *
+ * The peer is tracked here instead of `connect`ing the socket, so that a
+ * player coming back from another address can take over the session. */
+pub struct Connection {
+    /* Shared with the farewell, which may have to be sent from another thread. */
+    socket: Arc<Mutex<Box<dyn Transport>>>,
+    peer: Option<SocketAddr>,
+    pub stats: PacketStats,
+    pub heartbeat: Heartbeat,
+    reassembly: Reassembly,
+}
+
+impl Connection {
+    pub fn new(socket: Box<dyn Transport>, peer: Option<SocketAddr>, timeout: Duration) -> Self {
+        Connection {
+            socket: Arc::new(Mutex::new(socket)),
+            peer,
+            stats: PacketStats::default(),
+            heartbeat: Heartbeat::new(timeout),
+            reassembly: Reassembly::default(),
+        }
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.socket.lock().unwrap().local_addr().ok()
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    pub fn set_peer(&mut self, peer: SocketAddr) {
+        self.peer = Some(peer);
+        self.heartbeat.heard();
+    }
+
+    /* What it takes to tell the peer we are gone, once this connection can't be used any more. */
+    pub fn farewell(&self) -> Option<Farewell> {
+        self.peer.map(|peer| Farewell{socket: Arc::clone(&self.socket), peer})
+    }
+
+    pub fn send(&mut self, command: &COMMANDS) {
+        if let Some(peer) = self.peer {
+            self.send_to(command, peer);
+        }
+    }
+
+    pub fn send_to(&mut self, command: &COMMANDS, address: SocketAddr) {
+        let socket = self.socket.lock().unwrap();
+        for datagram in encode_datagrams(command) {
+            if socket.send_to(&datagram, address).is_err() {
+                self.stats.io_errors += 1;
+            }
+        }
+    }
+
+    /* Sends a heartbeat once per interval, so a silent peer can tell we are still here. */
+    pub fn keep_alive(&mut self) {
+        if self.heartbeat.due() {
+            self.send(&COMMANDS::Heartbeat);
+        }
+    }
+
+    /* Receives a single command.
+     *
+     * Commands from the peer are returned as they are. From any other address
+     * only requests to join are let through; everything else, and datagrams
+     * that fail to decode, are counted and skipped, so `Ok(None)` only means
+     * there was nothing usable to read. */
+    pub fn receive(&mut self, buffer: &mut [u8]) -> io::Result<Option<(COMMANDS, SocketAddr)>> {
+        let (length, source) = self.socket.lock().unwrap().recv_from(buffer)?;
+        let from_peer = self.peer == Some(source);
+
+        let command = match decode(&buffer[..length]) {
+            Ok(command) => command,
+            Err(error @ ProtocolError::VersionMismatch{..}) if !from_peer => {
+                // Someone is trying to join with another version, tell them why it fails.
+                self.stats.malformed += 1;
+                self.send_to(&COMMANDS::Reject(error.to_string()), source);
+                return Ok(None);
+            },
+            Err(_) => {
+                self.stats.malformed += 1;
+                return Ok(None);
+            },
+        };
+
+        if from_peer {
+            self.stats.received += 1;
+            self.stats.bytes += length as u64;
+            self.stats.last_size = length;
+            self.stats.largest = self.stats.largest.max(length);
+            self.heartbeat.heard();
+            return match command {
+                COMMANDS::Fragment(fragment) => {
+                    self.stats.fragments += 1;
+                    Ok(self.reassemble(fragment).map(|command| (command, source)))
+                },
+                command => Ok(Some((command, source))),
+            };
+        }
+        match command {
+            COMMANDS::Connect{..} | COMMANDS::Reconnect{..} => Ok(Some((command, source))),
+            _ => {
+                self.stats.unknown_peer += 1;
+                Ok(None)
+            },
+        }
+    }
+
+    fn reassemble(&mut self, fragment: Fragment) -> Option<COMMANDS> {
+        let packet = self.reassembly.add(fragment)?;
+        match decode(&packet) {
+            // Fragments of fragments are never sent.
+            Ok(COMMANDS::Fragment(_)) | Err(_) => {
+                self.stats.malformed += 1;
+                None
+            },
+            Ok(command) => Some(command),
+        }
+    }
+
+    /* Client side of the handshake: send the join request until the server answers with its settings. */
+    pub fn join(&mut self, request: &COMMANDS) -> Result<GameSettings, ConnectError> {
+        let mut buffer = [0; 10000];
+        let started = Instant::now();
+        let mut retry = Interval::new(HANDSHAKE_RETRY);
+
+        while started.elapsed() < Duration::from_millis(HANDSHAKE_TIMEOUT) {
+            if shutdown::requested() {
+                return Err(ConnectError::Interrupted);
+            }
+            if retry.ready() {
+                self.send(request);
+            }
+
+            let received = self.socket.lock().unwrap().recv_from(&mut buffer);
+            match received {
+                Ok((length, source)) if self.peer == Some(source) => {
+                    match decode(&buffer[..length]) {
+                        Ok(COMMANDS::Accept(settings)) => {
+                            self.heartbeat.heard();
+                            return Ok(settings);
+                        },
+                        Ok(COMMANDS::Reject(reason)) => return Err(ConnectError::Rejected(reason)),
+                        Ok(_) => {},
+                        Err(error @ ProtocolError::VersionMismatch{..}) => return Err(ConnectError::Protocol(error)),
+                        Err(_) => self.stats.malformed += 1,
+                    }
+                },
+                Ok(_) => self.stats.unknown_peer += 1,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                // Nobody listens on the server port yet, keep trying until the timeout.
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {},
+                Err(e) => return Err(ConnectError::Io(e)),
+            }
+            sleep(Duration::from_millis(10));
+        }
+        Err(ConnectError::Timeout)
+    }
+}
+
+/* The end of a match is sent again and again until the peer acknowledges it,
+ * because a single lost `Endgame` would leave the peer playing alone. */
+pub struct EndgameNotice {
+    pending: bool,
+    interval: Interval,
+}
+
+impl EndgameNotice {
+    pub fn new(pending: bool) -> Self {
+        EndgameNotice { pending, interval: Interval::new(ENDGAME_RESEND) }
+    }
+
+    pub fn resend(&mut self, connection: &mut Connection) {
+        if self.due(&connection.heartbeat) {
+            connection.send(&COMMANDS::Endgame);
+        }
+    }
+
+    /* True when the notice should go out again, a peer that went silent is given up on. */
+    pub fn due(&mut self, heartbeat: &Heartbeat) -> bool {
+        if heartbeat.timed_out() {
+            self.pending = false;
+        }
+        self.pending && self.interval.ready()
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+}
+
+pub enum ConnectError {
+    Rejected(String),
+    Protocol(ProtocolError),
+    Timeout,
+    Interrupted,
+    Io(io::Error),
+    /* The address given for the server doesn't name any. */
+    Unresolved(String),
+    /* The socket couldn't be opened, or a stream transport couldn't connect. */
+    Unreachable(io::Error),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectError::Rejected(reason) => write!(f, "server rejected the connection: {}", reason),
+            ConnectError::Protocol(error) => write!(f, "couldn't talk to the server: {}", error),
+            ConnectError::Timeout => write!(f, "server did not answer in {} seconds", HANDSHAKE_TIMEOUT / 1000),
+            ConnectError::Interrupted => write!(f, "interrupted while connecting"),
+            ConnectError::Io(error) => write!(f, "encountered IO error: {}", error),
+            ConnectError::Unresolved(address) => write!(f, "couldn't find the server {}", address),
+            ConnectError::Unreachable(error) => write!(f, "couldn't open the connection to the server: {}", error),
+        }
+    }
+}
+
+/* Address to bind to: `--bind` may leave out the port, then `default_port` is used. */
+fn bind_address(bind: Option<&str>, unspecified: IpAddr, default_port: u16) -> SocketAddr {
+    match bind {
+        Some(bind) => bind.parse::<SocketAddr>()
+            .or_else(|_| bind.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, default_port)))
+            .expect("Unable to parse bind address"),
+        None => SocketAddr::new(unspecified, default_port),
+    }
+}
+
+/* Address the server listens on, any IPv4 address unless `--bind` says otherwise. */
+pub fn listen_address(bind: Option<&str>, port: &str) -> SocketAddr {
+    let port = port.parse::<u16>().expect("Unable to parse port number");
+    bind_address(bind, Ipv4Addr::UNSPECIFIED.into(), port)
+}
+
+/* Opens the connection to the server, single player needs none.
+ *
+ * The client gets a port picked by the system unless `bind` says otherwise,
+ * so a client and a server can run on the same machine. */
+pub fn init_network (game: &Game, bind: Option<&str>, address: &str, timeout: Duration,
+                     transport: TransportKind, key: Option<Key>) -> Result<Option<Connection>, ConnectError> {
+    if let MODE::Single = game.get_mode() {
+        return Ok(None);
+    }
+
+    let target_server_address = address.to_socket_addrs().ok()
+        .and_then(|mut addresses| addresses.next())
+        .ok_or_else(|| ConnectError::Unresolved(address.to_string()))?;
+    let unspecified: IpAddr = match target_server_address {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let mut socket = transport.connect(bind_address(bind, unspecified, 0), target_server_address)
+        .map_err(ConnectError::Unreachable)?;
+    if let Some(key) = key {
+        socket = Box::new(Authenticated::client(socket, key));
+    }
+    Ok(Some(Connection::new(socket, Some(target_server_address), timeout)))
+}
+
+/* Ends the game with the peer when the program can't do it the usual way, because it panicked
+ * or got killed. Nothing is resent, so the one Endgame goes out a few times. */
+pub struct Farewell {
+    socket: Arc<Mutex<Box<dyn Transport>>>,
+    peer: SocketAddr,
+}
+
+impl Farewell {
+    pub fn send(&self) {
+        // The thread that panicked may be holding the socket, then the peer has to time out.
+        let socket = match self.socket.try_lock() {
+            Ok(socket) => socket,
+            Err(_) => return,
+        };
+        for _ in 0..3 {
+            for datagram in encode_datagrams(&COMMANDS::Endgame) {
+                let _ = socket.send_to(&datagram, self.peer);
+            }
+        }
+    }
+}
+
+/* Names are shown in the status window, so keep them short and printable. */
+pub fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LENGTH)
+        .collect()
+}
+
+/* Chat lines are printed as they are, so the same goes for them. */
+pub fn sanitize_chat(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_CHAT_LENGTH)
+        .collect()
+}
+
+/* Checks a connection request, returns the player name to use or the reason to reject it. */
+pub fn validate_connect(command: &COMMANDS) -> Result<String, String> {
+    match command {
+        COMMANDS::Connect{version, name} if *version == PROTOCOL_VERSION => Ok(sanitize_name(name)),
+        COMMANDS::Connect{version, ..} =>
+            Err(format!("server speaks protocol version {}, client speaks version {}", PROTOCOL_VERSION, version)),
+        _ => Err(String::from("expected a connection request")),
+    }
+}
+
+/* Checks a request to take over an existing session, returns the token of the session it asks for
+ * or the reason to reject it. Whether there is such a session is up to the caller. */
+pub fn validate_reconnect(command: &COMMANDS) -> Result<u64, String> {
+    match command {
+        COMMANDS::Reconnect{version, token} if *version == PROTOCOL_VERSION => Ok(*token),
+        COMMANDS::Reconnect{version, ..} =>
+            Err(format!("server speaks protocol version {}, client speaks version {}", PROTOCOL_VERSION, version)),
+        _ => Err(String::from("expected a reconnection request")),
+    }
 }
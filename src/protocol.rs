@@ -0,0 +1,311 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::game::{Field, Food, DIRECTION};
+
+/* Every datagram starts with a fixed header:
+ * magic (4 bytes), protocol version (2), message type (1), payload length (4). */
+pub const MAGIC: [u8; 4] = *b"RSNK";
+/* Raised only when the wire format changes, so that peers which can't play together refuse each other. */
+pub const PROTOCOL_VERSION: u16 = 11;
+pub const HEADER_LENGTH: usize = 11;
+/* Larger messages are split into fragments, so no datagram goes over the MTU. */
+pub const MAX_DATAGRAM: usize = 1200;
+const FRAGMENT_DATA: usize = 1100;
+/* Room kept free in every datagram for the signature of a private server. */
+const SIGNATURE_OVERHEAD: usize = 64;
+pub const MAX_FRAGMENTS: u16 = 64;
+/* Snake colors a player can pick from, the client decides what they look like. */
+pub const PLAYER_COLORS: u8 = 6;
+
+#[derive(Serialize, Deserialize)]
+pub enum COMMANDS {
+    Connect{version: u16, name: String},
+    Accept(GameSettings),
+    Reject(String),
+    Endgame,
+    Key{seq: u64, key: i32},
+    ServerData(UdpFrame),
+    Heartbeat,
+    EndgameAck,
+    Reconnect{version: u16, token: u64},
+    RoomList(Vec<RoomInfo>),
+    CreateRoom(String),
+    JoinRoom(u32),
+    RoomUpdate(RoomStatus),
+    Ready(bool),
+    Color(u8),
+    StartMatch,
+    WatchRoom(u32),
+    Announce(ServerInfo),
+    Probe(u64),
+    ProbeReply(u64),
+    ServerDelta(DeltaFrame),
+    FrameAck(u64),
+    Fragment(Fragment),
+    Inputs(InputBatch),
+    Checksum{tick: u64, checksum: u64},
+    Chat(ChatMessage),
+    Ping(u64),
+    Pong(u64),
+    Signed(SignedPacket),
+    Challenge(u64),
+}
+
+impl COMMANDS {
+    /* Message type written to the header. */
+    pub fn kind(&self) -> u8 {
+        match self {
+            COMMANDS::Connect{..} => 0,
+            COMMANDS::Accept(_) => 1,
+            COMMANDS::Reject(_) => 2,
+            COMMANDS::Endgame => 3,
+            COMMANDS::Key{..} => 4,
+            COMMANDS::ServerData(_) => 5,
+            COMMANDS::Heartbeat => 6,
+            COMMANDS::EndgameAck => 7,
+            COMMANDS::Reconnect{..} => 8,
+            COMMANDS::RoomList(_) => 9,
+            COMMANDS::CreateRoom(_) => 10,
+            COMMANDS::JoinRoom(_) => 11,
+            COMMANDS::RoomUpdate(_) => 12,
+            COMMANDS::Ready(_) => 13,
+            COMMANDS::Color(_) => 14,
+            COMMANDS::StartMatch => 15,
+            COMMANDS::WatchRoom(_) => 16,
+            COMMANDS::Announce(_) => 17,
+            COMMANDS::Probe(_) => 18,
+            COMMANDS::ProbeReply(_) => 19,
+            COMMANDS::ServerDelta(_) => 20,
+            COMMANDS::FrameAck(_) => 21,
+            COMMANDS::Fragment(_) => 22,
+            COMMANDS::Inputs(_) => 23,
+            COMMANDS::Checksum{..} => 24,
+            COMMANDS::Chat(_) => 25,
+            COMMANDS::Ping(_) => 26,
+            COMMANDS::Pong(_) => 27,
+            COMMANDS::Signed(_) => 28,
+            COMMANDS::Challenge(_) => 29,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UdpFrame {
+    pub tick: u64,
+    pub snake1: VecDeque<Field>,
+    pub snake2: VecDeque<Field>,
+    pub foods: Vec<Food>,
+    /* Last input of every player the server took into account, for the client's prediction. */
+    pub inputs: Vec<u64>,
+    pub refresh_timeout: i32,
+}
+
+/* How a snake changed since the baseline: fields added at the head, newest first, and fields gone from the tail. */
+#[derive(Serialize, Deserialize)]
+pub struct SnakeDelta {
+    pub head: Vec<Field>,
+    pub removed: u32,
+}
+
+/* A frame as the difference to an earlier one the client acknowledged. */
+#[derive(Serialize, Deserialize)]
+pub struct DeltaFrame {
+    pub tick: u64,
+    pub baseline: u64,
+    pub snakes: Vec<SnakeDelta>,
+    /* Only sent when the food changed since the baseline. */
+    pub foods: Option<Vec<Food>>,
+    pub inputs: Vec<u64>,
+    pub refresh_timeout: i32,
+}
+
+/* One piece of a message too large for a single datagram, `data` is part of its encoded packet. */
+#[derive(Serialize, Deserialize)]
+pub struct Fragment {
+    pub message: u32,
+    pub index: u16,
+    pub count: u16,
+    pub data: Vec<u8>,
+}
+
+/* Directions of every snake for the ticks starting at `first`, all the ticks the client
+ * has not acknowledged yet, so a lost batch is made up for by the next one. */
+#[derive(Serialize, Deserialize)]
+pub struct InputBatch {
+    pub first: u64,
+    pub directions: Vec<Vec<DIRECTION>>,
+}
+
+/* A line of chat. The server fills in who sent it, nobody at all means the server itself. */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub from: String,
+    pub text: String,
+}
+
+/* A packet together with the MAC proving it comes from someone knowing the password. */
+#[derive(Serialize, Deserialize)]
+pub struct SignedPacket {
+    pub packet: Vec<u8>,
+    /* Seconds since the epoch when it was signed, part of what the tag covers. */
+    pub time: u64,
+    pub tag: [u8; 16],
+}
+
+/* Settings the server sends back to an accepted client. */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GameSettings {
+    pub game_area_height: i32,
+    pub game_area_width: i32,
+    pub food_count: u32,
+    pub player_names: Vec<String>,
+    /* Lets the client take its slot back after losing the connection. */
+    pub session_token: u64,
+    /* Which of the snakes in `UdpFrame` belongs to the client, none when it only watches. */
+    pub player: u32,
+    pub spectator: bool,
+    pub player_colors: Vec<u8>,
+    /* None while the client is still in the lobby. */
+    pub room: Option<u32>,
+    /* Seed of the match when the room runs in lockstep, the clients then simulate it themselves. */
+    pub lockstep: Option<u64>,
+}
+
+/* A room as listed in the lobby. */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub id: u32,
+    pub name: String,
+    pub players: u32,
+    pub capacity: u32,
+    pub spectators: u32,
+    pub playing: bool,
+}
+
+/* What a server tells the local network about itself. */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub name: String,
+    pub game_area_height: i32,
+    pub game_area_width: i32,
+    pub players: u32,
+    pub rooms: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PlayerInfo {
+    pub name: String,
+    pub color: u8,
+    pub ready: bool,
+}
+
+/* Everything the players waiting in a room see, the first player is the host. */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoomStatus {
+    pub id: u32,
+    pub name: String,
+    pub players: Vec<PlayerInfo>,
+    pub countdown: Option<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    Truncated,
+    BadMagic,
+    VersionMismatch{local: u16, remote: u16},
+    KindMismatch,
+    Malformed,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated => write!(f, "packet is shorter than its header says"),
+            ProtocolError::BadMagic => write!(f, "packet is not a Rusty Snake message"),
+            ProtocolError::VersionMismatch{local, remote} =>
+                write!(f, "protocol version mismatch: we speak version {}, the other side speaks version {}",
+                       local, remote),
+            ProtocolError::KindMismatch => write!(f, "message type does not match its payload"),
+            ProtocolError::Malformed => write!(f, "packet payload could not be decoded"),
+        }
+    }
+}
+
+pub fn encode(command: &COMMANDS) -> Vec<u8> {
+    let payload = bincode::serialize(command).unwrap();
+
+    let mut packet = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    packet.extend_from_slice(&MAGIC);
+    packet.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    packet.push(command.kind());
+    packet.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    packet.extend_from_slice(&payload);
+    packet
+}
+
+/* The datagrams to send for a command, more than one only when it has to be fragmented. */
+pub fn encode_datagrams(command: &COMMANDS) -> Vec<Vec<u8>> {
+    static NEXT_MESSAGE: AtomicU32 = AtomicU32::new(0);
+
+    let packet = encode(command);
+    if packet.len() <= MAX_DATAGRAM - SIGNATURE_OVERHEAD {
+        return vec![packet];
+    }
+    let message = NEXT_MESSAGE.fetch_add(1, Ordering::Relaxed);
+    let count = packet.chunks(FRAGMENT_DATA).count() as u16;
+    packet.chunks(FRAGMENT_DATA)
+        .enumerate()
+        .map(|(index, data)| encode(&COMMANDS::Fragment(Fragment{message, index: index as u16, count, data: data.to_vec()})))
+        .collect()
+}
+
+/* Length of the packet at the start of a byte stream, once enough of it arrived to tell.
+ * Stream transports carry the same packets as datagrams, so none is ever larger. */
+pub fn packet_length(stream: &[u8]) -> Result<Option<usize>, ProtocolError> {
+    if stream.len() < HEADER_LENGTH {
+        return Ok(None);
+    }
+    if stream[0..4] != MAGIC {
+        return Err(ProtocolError::BadMagic);
+    }
+    let length = HEADER_LENGTH + u32::from_le_bytes(stream[7..11].try_into().unwrap()) as usize;
+    if length > MAX_DATAGRAM {
+        return Err(ProtocolError::Malformed);
+    }
+    Ok(Some(length))
+}
+
+/* Message type of a packet, without decoding the rest of it. */
+pub fn packet_kind(packet: &[u8]) -> Option<u8> {
+    packet.get(6).copied()
+}
+
+pub fn decode(packet: &[u8]) -> Result<COMMANDS, ProtocolError> {
+    if packet.len() < HEADER_LENGTH {
+        return Err(ProtocolError::Truncated);
+    }
+    if packet[0..4] != MAGIC {
+        return Err(ProtocolError::BadMagic);
+    }
+    let version = u16::from_le_bytes(packet[4..6].try_into().unwrap());
+    if version != PROTOCOL_VERSION {
+        return Err(ProtocolError::VersionMismatch{local: PROTOCOL_VERSION, remote: version});
+    }
+    let kind = packet[6];
+    let length = u32::from_le_bytes(packet[7..11].try_into().unwrap()) as usize;
+    if packet.len() - HEADER_LENGTH != length {
+        return Err(ProtocolError::Truncated);
+    }
+
+    let command: COMMANDS = bincode::deserialize(&packet[HEADER_LENGTH..])
+        .map_err(|_| ProtocolError::Malformed)?;
+    if command.kind() != kind {
+        return Err(ProtocolError::KindMismatch);
+    }
+    Ok(command)
+}
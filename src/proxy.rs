@@ -0,0 +1,158 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+/* A reordered datagram is held back this much longer than the ones after it. */
+static REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/* How bad the network between the clients and the server is. Chances are from 0 to 1. */
+#[derive(Clone, Copy, Default)]
+pub struct Impairment {
+    pub loss: f64,
+    pub latency: Duration,
+    /* Every datagram is delayed by up to this much more than the latency. */
+    pub jitter: Duration,
+    pub duplicate: f64,
+    pub reorder: f64,
+}
+
+/* What the proxy did to the datagrams passing through it. */
+#[derive(Default)]
+pub struct ProxyStats {
+    pub forwarded: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+/* A client seen by the proxy. It talks to the server from its own socket, so the server
+ * tells the clients apart by their address the same as without the proxy. */
+struct Link {
+    client: SocketAddr,
+    upstream: UdpSocket,
+}
+
+struct Delayed {
+    due: Instant,
+    link: usize,
+    to_server: bool,
+    data: Vec<u8>,
+}
+
+/* UDP proxy standing in for a bad network, for testing multiplayer on one machine.
+ *
+ * Clients connect to the proxy instead of the server. Whatever passes through in either
+ * direction may get lost, delayed, duplicated or overtaken by later datagrams. */
+pub struct Proxy {
+    socket: UdpSocket,
+    server: SocketAddr,
+    impairment: Impairment,
+    links: Vec<Link>,
+    queue: Vec<Delayed>,
+    rng: StdRng,
+    pub stats: ProxyStats,
+}
+
+impl Proxy {
+    pub fn bind(address: SocketAddr, server: SocketAddr, impairment: Impairment) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Proxy {
+            socket,
+            server,
+            impairment,
+            links: vec![],
+            queue: vec![],
+            rng: StdRng::from_entropy(),
+            stats: ProxyStats::default(),
+        })
+    }
+
+    /* The same impairments every run, for tests that have to be repeatable. */
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+
+    pub fn run(mut self) {
+        loop {
+            self.poll();
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    /* Takes in everything received so far and sends on what is due. */
+    pub fn poll(&mut self) {
+        let mut buffer = [0; 10000];
+        while let Ok((length, source)) = self.socket.recv_from(&mut buffer) {
+            let link = self.link(source);
+            if let Some(link) = link {
+                self.impair(link, true, &buffer[..length]);
+            }
+        }
+        for link in 0..self.links.len() {
+            while let Ok((length, source)) = self.links[link].upstream.recv_from(&mut buffer) {
+                if source == self.server {
+                    self.impair(link, false, &buffer[..length]);
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let (due, waiting): (Vec<Delayed>, Vec<Delayed>) = self.queue.drain(..).partition(|packet| packet.due <= now);
+        self.queue = waiting;
+        for packet in due {
+            let link = &self.links[packet.link];
+            let _ = match packet.to_server {
+                true => link.upstream.send_to(&packet.data, self.server),
+                false => self.socket.send_to(&packet.data, link.client),
+            };
+        }
+    }
+
+    /* Index of the client's link, a new client gets one. */
+    fn link(&mut self, client: SocketAddr) -> Option<usize> {
+        if let Some(link) = self.links.iter().position(|link| link.client == client) {
+            return Some(link);
+        }
+        let unspecified: IpAddr = match self.server {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let upstream = UdpSocket::bind(SocketAddr::new(unspecified, 0)).ok()?;
+        upstream.set_nonblocking(true).ok()?;
+        self.links.push(Link{client, upstream});
+        Some(self.links.len() - 1)
+    }
+
+    fn impair(&mut self, link: usize, to_server: bool, data: &[u8]) {
+        if self.rng.gen_bool(self.impairment.loss) {
+            self.stats.lost += 1;
+            return;
+        }
+        let copies = match self.rng.gen_bool(self.impairment.duplicate) {
+            true => {
+                self.stats.duplicated += 1;
+                2
+            },
+            false => 1,
+        };
+        for _ in 0..copies {
+            let jitter = self.impairment.jitter.as_micros() as u64;
+            let mut delay = self.impairment.latency + Duration::from_micros(self.rng.gen_range(0, jitter + 1));
+            if self.rng.gen_bool(self.impairment.reorder) {
+                self.stats.reordered += 1;
+                delay += REORDER_DELAY;
+            }
+            self.stats.forwarded += 1;
+            self.queue.push(Delayed{due: Instant::now() + delay, link, to_server, data: data.to_vec()});
+        }
+    }
+}
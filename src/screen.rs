@@ -1,21 +1,54 @@
-use crate::game::{Snake, DIRECTION, Game, MODE};
-use crate::{print_block};
+use crate::game::{Snake, DIRECTION, Game, MODE, FoodKind};
 use ncurses::*;
-use std::net::UdpSocket;
+use crate::discovery::DiscoveredServer;
+use crate::network::{Bandwidth, Connection, Latency, PacketStats, TickRate};
+use crate::protocol::{ChatMessage, RoomInfo, RoomStatus};
 
-pub(crate) static GAME_AREA_HEIGHT: i32 = 20;
-pub(crate) static GAME_AREA_WIDTH: i32 = 60;
+pub static GAME_AREA_HEIGHT: i32 = 20;
+pub static GAME_AREA_WIDTH: i32 = 60;
 static GAME_ENDING_HEIGHT: i32 = 10;
 static GAME_ENDING_WIDTH: i32 = 30;
 static STATUS_AREA_WIDTH: i32 = 30;
+static CHAT_HEIGHT: i32 = 10;
+/* A chat window any smaller than this has no room for messages. */
+static CHAT_MIN_HEIGHT: i32 = 4;
 
-pub fn print_ending_info (win: WINDOW, score1: &i32, score2: &i32) {
+/* Why the match ended, shown above the result. */
+pub enum EndReason {
+    GameOver,
+    BoardFull,
+    Disconnected,
+}
+
+pub fn print_ending_info (win: WINDOW, score1: &i32, score2: &i32, reason: &EndReason) {
     mvwaddstr(win, 2, 10, "GAME OVER");
     mvwaddstr(win, 7, 6, "Press 'q' to exit.");
+    match reason {
+        EndReason::GameOver => {},
+        EndReason::BoardFull => { mvwaddstr(win, 1, 10, "BOARD FULL"); },
+        EndReason::Disconnected => { mvwaddstr(win, 1, 4, "OPPONENT DISCONNECTED"); },
+    }
     if score1 > score2 { mvwaddstr(win, 3, 11, "YOU WIN!"); }
     else if score1 == score2 { mvwaddstr(win, 3, 8, "WE HAVE A TIE!"); }
     else { mvwaddstr(win, 3, 10, "YOU LOSE!"); }
-    mvwaddstr(win, 5, 3, &*format!("Player1: {}   Player2: {}", score1, score2));
+    mvwaddstr(win, 5, 3, &format!("Player1: {}   Player2: {}", score1, score2));
+    wrefresh(win);
+}
+
+/* A spectator has no side, so the result names the winner instead. */
+pub fn print_match_result(win: WINDOW, names: &[String], score1: &i32, score2: &i32, reason: &EndReason) {
+    mvwaddstr(win, 2, 10, "GAME OVER");
+    mvwaddstr(win, 7, 6, "Press 'q' to exit.");
+    match reason {
+        EndReason::GameOver => {},
+        EndReason::BoardFull => { mvwaddstr(win, 1, 10, "BOARD FULL"); },
+        EndReason::Disconnected => { mvwaddstr(win, 1, 6, "SERVER DISCONNECTED"); },
+    }
+    let result = if score1 > score2 { format!("{} WINS!", names[0]) }
+        else if score1 == score2 { String::from("WE HAVE A TIE!") }
+        else { format!("{} WINS!", names[1]) };
+    mvwaddstr(win, 3, (GAME_ENDING_WIDTH - result.len() as i32) / 2, &result);
+    mvwaddstr(win, 5, 2, &format!("{:.10}: {}   {:.10}: {}", names[0], score1, names[1], score2));
     wrefresh(win);
 }
 
@@ -35,44 +68,94 @@ pub fn create_ending_window() -> WINDOW {
     win
 }
 
-pub fn update_score(win: WINDOW, socket: &UdpSocket, mode: &MODE, player1: &i32, player2: &i32) {
+fn address_text(address: Option<std::net::SocketAddr>) -> String {
+    match address {
+        Some(address) => format!("{:<23.23}", address.to_string()),
+        None => format!("{:<23}", "     ------"),
+    }
+}
+
+pub fn update_score(win: WINDOW, connection: Option<&Connection>, mode: &MODE, names: &[String], player1: &i32, player2: &i32) {
     wattr_on(win, COLOR_PAIR(COLOR_PAIR_FOOD));
 
-    match mode {
-        MODE::Server => {
-            mvwaddstr(win, 6, 1,  "    Multiplayer server      ");
-            let local_address = socket.local_addr().unwrap();
-            mvwaddstr(win, 9, 5, &*format!("{}", local_address));
-            let client_address = socket.peer_addr().unwrap();
-            mvwaddstr(win, 12, 5, &*format!("{}", client_address));
-        },
-        MODE::Client => {
-            mvwaddstr(win, 6, 1,  "    Multiplayer client      ");
-            let client_address = socket.peer_addr().unwrap();
-            mvwaddstr(win, 9, 5, &*format!("{}", client_address));
-            let local_address = socket.local_addr().unwrap();
-            mvwaddstr(win, 12, 5, &*format!("{}", local_address));
+    /* The host plays through its own server as well, so both talk to a server over `connection`. */
+    if let Some(connection) = connection {
+        match mode {
+            MODE::Server => { mvwaddstr(win, 6, 1,  "    Multiplayer server      "); },
+            MODE::Spectator => { mvwaddstr(win, 6, 1,  "    Spectator               "); },
+            _ => { mvwaddstr(win, 6, 1,  "    Multiplayer client      "); },
         }
-        _ => {}
+        mvwaddstr(win, 9, 5, &address_text(connection.peer_addr()));
+        mvwaddstr(win, 11, 5, &address_text(connection.local_addr()));
     }
 
     wattr_on(win, COLOR_PAIR(COLOR_PAIR_SNK1_SC));
-    mvwaddstr(win, 16, 1, &*format!("    Player 1 score:  {}     ", player1));
+    mvwaddstr(win, 16, 1, &format!(" {:>16}:  {:<8}", names[0], player1));
     wattr_on(win, COLOR_PAIR(COLOR_PAIR_SNK2_SC));
-    mvwaddstr(win, 17, 1, &*format!("    Player 2 score:  {}     ", player2));
+    mvwaddstr(win, 17, 1, &format!(" {:>16}:  {:<8}", names[1], player2));
+}
+
+pub fn print_session(win: WINDOW, session_token: u64) {
+    wattr_on(win, COLOR_PAIR(COLOR_PAIR_FOOD));
+    mvwaddstr(win, 7, 1, &format!(" Session {:016x}   ", session_token));
+}
+
+/* One line for connection problems, an empty message clears it. */
+pub fn print_status_message(win: WINDOW, message: &str) {
+    wattr_on(win, COLOR_PAIR(COLOR_PAIR_FOOD));
+    mvwaddstr(win, 3, 1, &format!("{:^28}", message));
+}
+
+/* How the connection to the server is doing, below the addresses. */
+pub fn print_latency(win: WINDOW, latency: &Latency, tick_rate: &TickRate) {
+    let ping = match latency.rtt {
+        Some(rtt) => format!("{:>5}", rtt.as_millis()),
+        None => String::from("  ---"),
+    };
+    let loss = match latency.loss() {
+        Some(loss) => format!("{:>3.0}%", loss),
+        None => String::from(" ---"),
+    };
+    wattr_on(win, COLOR_PAIR(COLOR_PAIR_FOOD));
+    mvwaddstr(win, 12, 1, &format!(" Ping {} ms Jitter {:>3} ms", ping, latency.jitter.as_millis().min(999)));
+    mvwaddstr(win, 13, 1, &format!(" Loss {}     Ticks {:>4.1}/s ", loss, tick_rate.ticks_per_second));
+}
+
+pub fn update_packet_stats(win: WINDOW, stats: &PacketStats) {
+    wattr_on(win, COLOR_PAIR(COLOR_PAIR_FOOD));
+    mvwaddstr(win, 18, 1, &format!(" Dropped {:<6} Errors {:<5}", stats.dropped(), stats.io_errors));
+}
+
+/* Traffic of the connection, in a corner of the screen the game doesn't use. */
+pub fn create_debug_window() -> WINDOW {
+    let win = newwin(6, 40, 1, 1);
+    box_(win, 0, 0);
+    wrefresh(win);
+    win
 }
 
-pub fn create_status_window() -> WINDOW {
+pub fn print_debug_overlay(win: WINDOW, stats: &PacketStats, bandwidth: &Bandwidth) {
+    wattr_on(win, COLOR_PAIR(COLOR_PAIR_FOOD));
+    mvwaddstr(win, 1, 2, "NETWORK");
+    mvwaddstr(win, 2, 2, &format!("In   {:>8.1} kB/s {:>6} packets/s",
+                                   bandwidth.bytes_per_second as f64 / 1000.0, bandwidth.packets_per_second));
+    mvwaddstr(win, 3, 2, &format!("Last {:>6} B     largest {:>6} B", stats.last_size, stats.largest));
+    mvwaddstr(win, 4, 2, &format!("Full {:<7} delta {:<7} frag {:<5}", stats.keyframes, stats.deltas, stats.fragments));
+    wrefresh(win);
+}
+
+pub fn create_status_window(game: &Game) -> WINDOW {
     /* Get the screen bounds. */
     let mut screen_max_y = 0;
     let mut screen_max_x = 0;
     getmaxyx(stdscr(), &mut screen_max_y, &mut screen_max_x);
+    let (area_height, area_width) = game.get_area_size();
 
     /* Start in the center. */
-    let start_y = (screen_max_y - GAME_AREA_HEIGHT) / 2;
-    let start_x = (screen_max_x - GAME_AREA_WIDTH - STATUS_AREA_WIDTH) / 2 - 2;
+    let start_y = (screen_max_y - area_height) / 2;
+    let start_x = (screen_max_x - area_width - STATUS_AREA_WIDTH) / 2 - 2;
 
-    let win = newwin(GAME_AREA_HEIGHT, STATUS_AREA_WIDTH, start_y, start_x);
+    let win = newwin(area_height, STATUS_AREA_WIDTH, start_y, start_x);
     box_(win, 0, 0);
 
     mvwaddstr(win, 1, 1,  "                            ");
@@ -81,8 +164,8 @@ pub fn create_status_window() -> WINDOW {
     mvwaddstr(win, 6, 1,  "       Single player        ");
     mvwaddstr(win, 8, 1,  "      Server address:       ");
     mvwaddstr(win, 9, 1,  "          ------            ");
-    mvwaddstr(win, 11, 1, "      Client address:       ");
-    mvwaddstr(win, 12, 1, "          ------            ");
+    mvwaddstr(win, 10, 1, "      Client address:       ");
+    mvwaddstr(win, 11, 1, "          ------            ");
     mvwaddstr(win, 14, 1, "----------------------------");
 
     wattr_on(win, COLOR_PAIR(COLOR_PAIR_SNK1_SC));
@@ -94,23 +177,79 @@ pub fn create_status_window() -> WINDOW {
     win
 }
 
+/* The chat goes below the status window, if the terminal is tall enough for it. */
+pub fn create_chat_window(game: &Game) -> Option<WINDOW> {
+    /* Get the screen bounds. */
+    let mut screen_max_y = 0;
+    let mut screen_max_x = 0;
+    getmaxyx(stdscr(), &mut screen_max_y, &mut screen_max_x);
+    let (area_height, area_width) = game.get_area_size();
+
+    /* Line up with the status window, leaving the last line of the screen alone. */
+    let start_y = (screen_max_y - area_height) / 2 + area_height;
+    let start_x = (screen_max_x - area_width - STATUS_AREA_WIDTH) / 2 - 2;
+    let height = CHAT_HEIGHT.min(screen_max_y - start_y - 1);
+    if height < CHAT_MIN_HEIGHT {
+        return None;
+    }
+
+    let win = newwin(height, STATUS_AREA_WIDTH, start_y, start_x);
+    print_chat(win, &[], None);
+    Some(win)
+}
+
+/* The newest messages at the bottom, above the line being typed. Long ones wrap. */
+pub fn print_chat(win: WINDOW, messages: &[ChatMessage], input: Option<&TextInput>) {
+    let height = getmaxy(win);
+    let width = (STATUS_AREA_WIDTH - 2) as usize;
+    werase(win);
+    box_(win, 0, 0);
+    wattr_on(win, COLOR_PAIR(COLOR_PAIR_FOOD));
+
+    let mut lines: Vec<String> = vec![];
+    for message in messages {
+        let line: Vec<char> = match message.from.is_empty() {
+            true => format!("* {}", message.text),
+            false => format!("{}: {}", message.from, message.text),
+        }.chars().collect();
+        lines.extend(line.chunks(width).map(|chunk| chunk.iter().collect::<String>()));
+    }
+    let rows = (height - 3) as usize;
+    for (row, line) in lines.iter().skip(lines.len().saturating_sub(rows)).enumerate() {
+        mvwaddstr(win, 1 + row as i32, 1, line);
+    }
+
+    match input {
+        Some(input) => {
+            // Only the end of a long line fits, that is where the typing happens.
+            let text: Vec<char> = format!("> {}_", input.text()).chars().collect();
+            let shown: String = text[text.len().saturating_sub(width)..].iter().collect();
+            mvwaddstr(win, height - 2, 1, &shown);
+        },
+        None => { mvwaddstr(win, height - 2, 1, "Press 't' to chat"); },
+    }
+    wrefresh(win);
+}
+
 pub fn create_game_area(game: &mut Game, snakes: &mut Vec<Snake>) -> WINDOW {
     /* Get the screen bounds. */
     let mut screen_max_y = 0;
     let mut screen_max_x = 0;
     getmaxyx(stdscr(), &mut screen_max_y, &mut screen_max_x);
+    let (area_height, area_width) = game.get_area_size();
 
     /* Start in the center. */
-    let start_y = (screen_max_y - GAME_AREA_HEIGHT) / 2;
-    let start_x = (screen_max_x - GAME_AREA_WIDTH + STATUS_AREA_WIDTH) / 2 + 2;
+    let start_y = (screen_max_y - area_height) / 2;
+    let start_x = (screen_max_x - area_width + STATUS_AREA_WIDTH) / 2 + 2;
     game.set_start(start_y, start_x);
 
-    snakes.push(Snake::new(start_y + GAME_AREA_HEIGHT/2, start_x + 2,
+    snakes.push(Snake::new(start_y + area_height/2, start_x + 2,
                            DIRECTION::Right, print_block, COLOR_PAIR_SNK1));
-    snakes.push(Snake::new(start_y + GAME_AREA_HEIGHT/2, start_x + GAME_AREA_WIDTH - 3,
+    snakes.push(Snake::new(start_y + area_height/2, start_x + area_width - 3,
                            DIRECTION::Left, print_block, COLOR_PAIR_SNK2));
+    game.init_grid(snakes);
 
-    let win = newwin(GAME_AREA_HEIGHT, GAME_AREA_WIDTH, start_y, start_x);
+    let win = newwin(area_height, area_width, start_y, start_x);
     box_(win, 0, 0);
     wrefresh(win);
     win
@@ -121,6 +260,153 @@ static COLOR_PAIR_SNK1_SC: i16 = 2;
 static COLOR_PAIR_SNK2_SC: i16 = 3;
 pub(crate) static COLOR_PAIR_SNK1: i16 = 4;
 pub(crate) static COLOR_PAIR_SNK2: i16 = 5;
+static COLOR_PAIR_FOOD_BONUS: i16 = 6;
+static COLOR_PAIR_FOOD_SHRINK: i16 = 7;
+static COLOR_PAIR_FOOD_SPEED_UP: i16 = 8;
+static COLOR_PAIR_FOOD_SLOW_DOWN: i16 = 9;
+static COLOR_PAIR_FOOD_GOLDEN: i16 = 10;
+/* One pair per color in `PLAYER_COLORS`, for names in the room. */
+static COLOR_PAIR_PLAYER: i16 = 11;
+
+/* What the colors players pick in a room look like, indexed by the color number. */
+static PLAYER_COLORS: [i16; 6] = [COLOR_RED, COLOR_BLUE, COLOR_GREEN, COLOR_YELLOW, COLOR_MAGENTA, COLOR_CYAN];
+
+pub enum InputEvent {
+    Editing,
+    Submitted(String),
+    Cancelled,
+}
+
+/* A single line of text typed in while the game keeps running, fed one key at a time. */
+pub struct TextInput {
+    text: String,
+    max_length: usize,
+}
+
+impl TextInput {
+    pub fn new(max_length: usize) -> Self {
+        TextInput { text: String::new(), max_length }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn handle_key(&mut self, key: i32) -> InputEvent {
+        match key {
+            10 | 13 | KEY_ENTER => InputEvent::Submitted(self.text.clone()),
+            27 => InputEvent::Cancelled,
+            8 | 127 | KEY_BACKSPACE => {
+                self.text.pop();
+                InputEvent::Editing
+            },
+            32..=126 if self.text.chars().count() < self.max_length => {
+                self.text.push(key as u8 as char);
+                InputEvent::Editing
+            },
+            _ => InputEvent::Editing,
+        }
+    }
+}
+
+/* Empties the game area, the lobby and the room are drawn there before the match. */
+pub fn clear_game_area(win: WINDOW) {
+    werase(win);
+    box_(win, 0, 0);
+    wrefresh(win);
+}
+
+pub fn print_lobby(win: WINDOW, rooms: &[RoomInfo], selected: usize, new_room: Option<&TextInput>, message: &str) {
+    let height = getmaxy(win);
+    werase(win);
+    box_(win, 0, 0);
+    wattr_on(win, COLOR_PAIR(COLOR_PAIR_FOOD));
+
+    mvwaddstr(win, 1, 2, "LOBBY");
+    mvwaddstr(win, 3, 2, &format!("{:<20} {:>8} {:>8}", "Room", "Players", "Watching"));
+    if rooms.is_empty() {
+        mvwaddstr(win, 5, 2, "No rooms yet, press 'n' to open one.");
+    }
+    for (index, room) in rooms.iter().enumerate().take((height - 10).max(0) as usize) {
+        let state = if room.playing { "playing" } else { "" };
+        let line = format!("{:<20} {:>6}/{:<1} {:>8} {:<8}", room.name, room.players, room.capacity, room.spectators, state);
+        if index == selected {
+            wattr_on(win, A_REVERSE());
+        }
+        mvwaddstr(win, 5 + index as i32, 2, &line);
+        wattr_off(win, A_REVERSE());
+    }
+
+    if let Some(input) = new_room {
+        mvwaddstr(win, height - 5, 2, &format!("Room name: {}_", input.text()));
+    }
+    mvwaddstr(win, height - 4, 2, message);
+    mvwaddstr(win, height - 3, 2, "Arrows select, Enter joins, 'w' watches, 'n' opens a new room");
+    mvwaddstr(win, height - 2, 2, "Press 'q' to exit");
+    wrefresh(win);
+}
+
+/* Servers found on the local network, drawn on the whole screen since the game area isn't known yet. */
+pub fn print_servers(win: WINDOW, servers: &[DiscoveredServer], selected: usize) {
+    let height = getmaxy(win);
+    werase(win);
+    box_(win, 0, 0);
+
+    mvwaddstr(win, 1, 2, "FIND GAMES");
+    mvwaddstr(win, 3, 2, &format!("{:<20} {:<23} {:>7} {:>7} {:>5} {:>8}",
+                                   "Server", "Address", "Map", "Players", "Rooms", "Ping"));
+    if servers.is_empty() {
+        mvwaddstr(win, 5, 2, "Looking for servers on the local network...");
+    }
+    for (index, server) in servers.iter().enumerate().take((height - 8).max(0) as usize) {
+        let ping = match server.ping {
+            Some(ping) => format!("{}ms", ping.as_millis()),
+            None => String::from("-"),
+        };
+        let map = format!("{}x{}", server.info.game_area_width, server.info.game_area_height);
+        let line = format!("{:<20.20} {:<23.23} {:>7} {:>7} {:>5} {:>8}",
+                           server.info.name, server.address.to_string(), map, server.info.players,
+                           server.info.rooms, ping);
+        if index == selected {
+            wattr_on(win, A_REVERSE());
+        }
+        mvwaddstr(win, 5 + index as i32, 2, &line);
+        wattr_off(win, A_REVERSE());
+    }
+
+    mvwaddstr(win, height - 3, 2, "Arrows select, Enter connects");
+    mvwaddstr(win, height - 2, 2, "Press 'q' to exit");
+    wrefresh(win);
+}
+
+/* Players in the room and, once the host started it, the countdown to the match. Spectators are nobody in the list. */
+pub fn print_room(win: WINDOW, status: &RoomStatus, me: Option<usize>) {
+    let height = getmaxy(win);
+    werase(win);
+    box_(win, 0, 0);
+    wattr_on(win, COLOR_PAIR(COLOR_PAIR_FOOD));
+
+    mvwaddstr(win, 1, 2, &format!("ROOM: {}", status.name));
+    for (index, player) in status.players.iter().enumerate() {
+        let ready = if player.ready { "ready" } else { "" };
+        let host = if index == 0 { "(host)" } else { "" };
+        let you = if Some(index) == me { "<- you" } else { "" };
+        wattr_on(win, COLOR_PAIR(COLOR_PAIR_PLAYER + (player.color as usize % PLAYER_COLORS.len()) as i16));
+        mvwaddstr(win, 3 + index as i32, 2, &format!("{:<16}", player.name));
+        wattr_on(win, COLOR_PAIR(COLOR_PAIR_FOOD));
+        mvwaddstr(win, 3 + index as i32, 19, &format!("{:<6} {:<6} {}", ready, host, you));
+    }
+
+    if let Some(countdown) = status.countdown {
+        mvwaddstr(win, height / 2 + 1, 2, &format!("Match starts in {}", countdown));
+    }
+    match me {
+        Some(_) => { mvwaddstr(win, height - 3, 2, "'r' ready, 'c' color, 's' start (host only)"); },
+        None => { mvwaddstr(win, height - 3, 2, "Watching, the match starts when the host says so"); },
+    }
+    mvwaddstr(win, height - 2, 2, "Press 'q' to exit");
+    wrefresh(win);
+}
 
 pub fn init_window_colors() {
     init_pair(COLOR_PAIR_FOOD, COLOR_WHITE, COLOR_BLACK);
@@ -128,6 +414,42 @@ pub fn init_window_colors() {
     init_pair(COLOR_PAIR_SNK2_SC, COLOR_BLACK, COLOR_BLUE);
     init_pair(COLOR_PAIR_SNK1, COLOR_RED, COLOR_BLACK);
     init_pair(COLOR_PAIR_SNK2, COLOR_BLUE, COLOR_BLACK);
+    init_pair(COLOR_PAIR_FOOD_BONUS, COLOR_GREEN, COLOR_BLACK);
+    init_pair(COLOR_PAIR_FOOD_SHRINK, COLOR_MAGENTA, COLOR_BLACK);
+    init_pair(COLOR_PAIR_FOOD_SPEED_UP, COLOR_CYAN, COLOR_BLACK);
+    init_pair(COLOR_PAIR_FOOD_SLOW_DOWN, COLOR_YELLOW, COLOR_BLACK);
+    init_pair(COLOR_PAIR_FOOD_GOLDEN, COLOR_BLACK, COLOR_YELLOW);
+    for (index, color) in PLAYER_COLORS.iter().enumerate() {
+        init_pair(COLOR_PAIR_PLAYER + index as i16, *color, COLOR_BLACK);
+    }
+}
+
+fn player_color(color: u8) -> i16 {
+    PLAYER_COLORS[color as usize % PLAYER_COLORS.len()]
+}
+
+/* Snakes and scores are drawn in the colors the players picked in the room. */
+pub fn set_player_colors(colors: &[u8]) {
+    if let Some(color) = colors.first() {
+        init_pair(COLOR_PAIR_SNK1_SC, COLOR_BLACK, player_color(*color));
+        init_pair(COLOR_PAIR_SNK1, player_color(*color), COLOR_BLACK);
+    }
+    if let Some(color) = colors.get(1) {
+        init_pair(COLOR_PAIR_SNK2_SC, COLOR_BLACK, player_color(*color));
+        init_pair(COLOR_PAIR_SNK2, player_color(*color), COLOR_BLACK);
+    }
+}
+
+/* Glyph and colour pair used to draw each kind of food. */
+pub fn food_style(kind: &FoodKind) -> (chtype, i16) {
+    match kind {
+        FoodKind::Normal => (ACS_DIAMOND(), COLOR_PAIR_FOOD),
+        FoodKind::Bonus => ('+' as chtype, COLOR_PAIR_FOOD_BONUS),
+        FoodKind::Shrink => ('-' as chtype, COLOR_PAIR_FOOD_SHRINK),
+        FoodKind::SpeedUp => ('>' as chtype, COLOR_PAIR_FOOD_SPEED_UP),
+        FoodKind::SlowDown => ('<' as chtype, COLOR_PAIR_FOOD_SLOW_DOWN),
+        FoodKind::Golden => ('$' as chtype, COLOR_PAIR_FOOD_GOLDEN),
+    }
 }
 
 
@@ -139,3 +461,19 @@ pub fn destroy_window(win: WINDOW)
     wrefresh(win);
     delwin(win);
 }
+
+pub fn print_block(y: &i32, x: &i32, color: &i16) {
+    attr_on(COLOR_PAIR(*color));
+    mvaddch(*y, *x, ACS_CKBOARD());
+}
+
+pub fn print_food(y: &i32, x: &i32, kind: &FoodKind) {
+    let (glyph, color) = food_style(kind);
+    attr_on(COLOR_PAIR(color));
+    mvaddch(*y, *x, glyph);
+}
+
+pub fn print_space(y: &i32, x: &i32) {
+    let ch = ' ' as chtype;
+    mvaddch(*y, *x, ch);
+}
@@ -0,0 +1,1104 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::auth::{Authenticated, Key};
+use crate::delta::{diff, FrameHistory, KEYFRAME_INTERVAL};
+use crate::discovery::{announce, ANNOUNCE_INTERVAL};
+use crate::game::{CHECKSUM_INTERVAL, DIRECTION, DIRECTIONS, FoodKind, Game, MODE, Snake};
+use crate::network::{sanitize_chat, sanitize_name, validate_connect, validate_reconnect, EndgameNotice, Heartbeat,
+                     Interval, PacketStats, RateLimit, SequenceFilter};
+use crate::protocol::{decode, encode_datagrams, ChatMessage, GameSettings, InputBatch, PlayerInfo, ProtocolError, RoomInfo, RoomStatus,
+                      ServerInfo, UdpFrame, COMMANDS, PLAYER_COLORS};
+use crate::shutdown;
+use crate::transport::{Transport, TransportKind};
+
+static PLAYERS_PER_ROOM: usize = 2;
+static COUNTDOWN: u8 = 3;
+static LOBBY_REFRESH: u64 = 500;
+/* Ticks of inputs kept for lockstep clients, one further behind gets frames instead. */
+static INPUT_HISTORY: usize = 64;
+static CHECKSUM_HISTORY: usize = 16;
+/* Everybody may say a few things at once, after that one line every two seconds. */
+static CHAT_BURST: u32 = 5;
+static CHAT_INTERVAL: u64 = 2000;
+/* A client may send this many packets at once, after that one every 10 ms. */
+static FLOOD_BURST: u32 = 100;
+static FLOOD_INTERVAL: u64 = 10;
+/* Packets dropped for flooding or for making no sense a client gets away with before it is kicked. */
+static MAX_STRIKES: u32 = 100;
+
+/* A hosted server shares its terminal with the game, only the dedicated one logs. */
+macro_rules! log {
+    ($log:expr, $($arg:tt)*) => {
+        if $log {
+            println!($($arg)*);
+        }
+    };
+}
+
+fn draw_block(_y: &i32, _x: &i32, _color: &i16) {}
+fn draw_food(_y: &i32, _x: &i32, _kind: &FoodKind) {}
+fn clear_field(_y: &i32, _x: &i32) {}
+
+pub struct ServerConfig {
+    /* Shown on the "find games" screen of the local network. */
+    pub name: String,
+    pub game_area_height: i32,
+    pub game_area_width: i32,
+    /* One per player plus one when not given. */
+    pub food_count: Option<usize>,
+    pub peer_timeout: Duration,
+    pub grace_period: Duration,
+    /* Print what happens on stdout. */
+    pub log: bool,
+    /* Rooms send only the inputs of every tick, the clients simulate the match themselves. */
+    pub lockstep: bool,
+    pub transport: TransportKind,
+    /* Only clients knowing it get in, and everything they send is signed with a key made from it. */
+    pub password: Option<String>,
+}
+
+struct Player {
+    address: SocketAddr,
+    name: String,
+    session_token: u64,
+    heartbeat: Heartbeat,
+    input_filter: SequenceFilter,
+    direction: DIRECTION,
+    endgame: EndgameNotice,
+    color: u8,
+    ready: bool,
+    /* Newest frame the client has, deltas are built on it. In lockstep the newest tick it simulated. */
+    acked: Option<u64>,
+    /* Simulates the match itself from the inputs, until it falls behind or out of sync. */
+    lockstep: bool,
+    chat_limit: RateLimit,
+    flood_limit: RateLimit,
+    strikes: u32,
+    /* Already changed direction this tick. */
+    turned: bool,
+    /* Newest input the coming step goes by: the turn it takes, or one without a key.
+     * Turns it leaves out are not among them, the client still has them to predict with. */
+    taken: u64,
+    /* The same for the last step, the client predicts only from the inputs after it. */
+    applied: u64,
+}
+
+impl Player {
+    fn new(address: SocketAddr, name: String, timeout: Duration) -> Self {
+        Player {
+            address,
+            name,
+            session_token: rand::random(),
+            heartbeat: Heartbeat::new(timeout),
+            input_filter: SequenceFilter::default(),
+            direction: DIRECTION::Left,
+            endgame: EndgameNotice::new(false),
+            color: 0,
+            ready: false,
+            acked: None,
+            lockstep: false,
+            chat_limit: RateLimit::new(CHAT_BURST, CHAT_INTERVAL),
+            flood_limit: RateLimit::new(FLOOD_BURST, FLOOD_INTERVAL),
+            strikes: 0,
+            turned: false,
+            taken: 0,
+            applied: 0,
+        }
+    }
+
+    /* Counts something the client should never have sent, only the first one is logged. */
+    fn strike(&mut self, what: &str, log: bool) {
+        self.strikes += 1;
+        if self.strikes == 1 {
+            log!(log, "{} at {} {}", self.name, self.address, what);
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum RoomState {
+    Waiting,
+    Countdown(u8),
+    Playing,
+    Finished,
+}
+
+/* A single match: its own board, snakes and players. */
+struct Room {
+    id: u32,
+    name: String,
+    game: Game,
+    snakes: Vec<Snake>,
+    players: Vec<Player>,
+    /* Watch the match without taking part, they never count as players. */
+    spectators: Vec<Player>,
+    state: RoomState,
+    history: FrameHistory,
+    /* Seed of the match when it runs in lockstep. */
+    seed: Option<u64>,
+    /* Directions of every snake per tick and checksums of the game, for lockstep clients. */
+    inputs: VecDeque<(u64, Vec<DIRECTION>)>,
+    checksums: VecDeque<(u64, u64)>,
+    last_step: Instant,
+    log: bool,
+    waiting_since: Option<Instant>,
+}
+
+impl Room {
+    fn new(id: u32, name: String, config: &ServerConfig) -> Self {
+        let height = config.game_area_height;
+        let width = config.game_area_width;
+        let mut game = Game::new(height, width, draw_food, draw_block, clear_field);
+        game.set_mode(MODE::Server);
+
+        /* Same starting positions as on the screen, relative to the game area. */
+        let snakes = vec![
+            Snake::new(height/2, 2, DIRECTION::Right, draw_block, 0),
+            Snake::new(height/2, width - 3, DIRECTION::Left, draw_block, 0),
+        ];
+        game.init_grid(&snakes);
+        game.set_food_count(config.food_count.unwrap_or(snakes.len() + 1));
+        game.init_food();
+
+        Room {
+            id,
+            name,
+            game,
+            snakes,
+            players: vec![],
+            spectators: vec![],
+            state: RoomState::Waiting,
+            history: FrameHistory::default(),
+            seed: if config.lockstep { Some(rand::random()) } else { None },
+            inputs: VecDeque::new(),
+            checksums: VecDeque::new(),
+            last_step: Instant::now(),
+            log: config.log,
+            waiting_since: None,
+        }
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.players.iter().map(|player| player.name.clone()).collect();
+        while names.len() < PLAYERS_PER_ROOM {
+            names.push(format!("Player {}", names.len() + 1));
+        }
+        names
+    }
+
+    /* What everybody in the room gets told, without anything about the one receiving it. */
+    fn settings_for_all(&self) -> GameSettings {
+        let (game_area_height, game_area_width) = self.game.get_area_size();
+        GameSettings {
+            game_area_height,
+            game_area_width,
+            food_count: self.game.get_food_count() as u32,
+            player_names: self.names(),
+            session_token: 0,
+            player: 0,
+            spectator: false,
+            player_colors: self.players.iter().map(|player| player.color).collect(),
+            room: Some(self.id),
+            lockstep: self.seed,
+        }
+    }
+
+    fn settings(&self, player: usize) -> GameSettings {
+        GameSettings {
+            session_token: self.players[player].session_token,
+            player: player as u32,
+            ..self.settings_for_all()
+        }
+    }
+
+    fn spectator_settings(&self, spectator: usize) -> GameSettings {
+        GameSettings {
+            session_token: self.spectators[spectator].session_token,
+            player: 0,
+            spectator: true,
+            // Spectators may come in at any tick, they always get frames.
+            lockstep: None,
+            ..self.settings_for_all()
+        }
+    }
+
+    fn info(&self) -> RoomInfo {
+        RoomInfo {
+            id: self.id,
+            name: self.name.clone(),
+            players: self.players.len() as u32,
+            capacity: PLAYERS_PER_ROOM as u32,
+            spectators: self.spectators.len() as u32,
+            playing: self.state != RoomState::Waiting,
+        }
+    }
+
+    fn status(&self) -> RoomStatus {
+        RoomStatus {
+            id: self.id,
+            name: self.name.clone(),
+            players: self.players.iter()
+                .map(|player| PlayerInfo{name: player.name.clone(), color: player.color, ready: player.ready})
+                .collect(),
+            countdown: match self.state {
+                RoomState::Countdown(remaining) => Some(remaining),
+                _ => None,
+            },
+        }
+    }
+
+    fn frame(&self) -> UdpFrame {
+        UdpFrame {
+            tick: self.game.get_tick(),
+            snake1: self.snakes[0].body.clone(),
+            snake2: self.snakes[1].body.clone(),
+            foods: self.game.get_foods_win(),
+            inputs: self.players.iter().map(|player| player.applied).collect(),
+            refresh_timeout: self.game.get_refresh_timeout(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.state == RoomState::Waiting && self.players.len() < PLAYERS_PER_ROOM
+    }
+
+    fn broadcast(&self, command: &COMMANDS, socket: &dyn Transport, stats: &mut PacketStats) {
+        for player in self.players.iter().chain(&self.spectators) {
+            send_to(socket, stats, command, player.address);
+        }
+    }
+
+    /* Adds a player, who keeps its color unless somebody in the room already has it. */
+    fn add(&mut self, mut player: Player, socket: &dyn Transport, stats: &mut PacketStats) {
+        let taken: Vec<u8> = self.players.iter().map(|player| player.color).collect();
+        if taken.contains(&player.color) {
+            player.color = (0..PLAYER_COLORS).find(|color| !taken.contains(color)).unwrap_or(0);
+        }
+        player.ready = false;
+        log!(self.log, "room {}: {} joined from {}", self.id, player.name, player.address);
+        self.players.push(player);
+
+        let index = self.players.len() - 1;
+        send_to(socket, stats, &COMMANDS::Accept(self.settings(index)), self.players[index].address);
+        self.broadcast(&COMMANDS::RoomUpdate(self.status()), socket, stats);
+    }
+
+    fn add_spectator(&mut self, spectator: Player, socket: &dyn Transport, stats: &mut PacketStats) {
+        log!(self.log, "room {}: {} watching from {}", self.id, spectator.name, spectator.address);
+        self.spectators.push(spectator);
+        self.welcome_spectator(self.spectators.len() - 1, socket, stats);
+    }
+
+    /* Somebody left before the match, whoever is first now hosts the room. */
+    fn leave(&mut self, index: usize, reason: &str, socket: &dyn Transport, stats: &mut PacketStats) {
+        let player = self.players.remove(index);
+        log!(self.log, "room {}: {} {}", self.id, player.name, reason);
+        self.state = RoomState::Waiting;
+        // Everybody behind the one who left moved up a place.
+        for index in index..self.players.len() {
+            send_to(socket, stats, &COMMANDS::Accept(self.settings(index)), self.players[index].address);
+        }
+        self.broadcast(&COMMANDS::RoomUpdate(self.status()), socket, stats);
+
+        // Nothing left to watch.
+        if self.players.is_empty() && !self.spectators.is_empty() {
+            self.finish("everybody left", None);
+        }
+    }
+
+    fn start(&mut self, socket: &dyn Transport, stats: &mut PacketStats) {
+        self.state = RoomState::Playing;
+        self.last_step = Instant::now();
+        log!(self.log, "room {}: {} started", self.id, self.names().join(" vs "));
+
+        for (player, snake) in self.players.iter_mut().zip(&self.snakes) {
+            player.direction = snake.direction.clone();
+            player.lockstep = self.seed.is_some();
+        }
+        if let Some(seed) = self.seed {
+            self.game.restart(&self.snakes, seed);
+        }
+        // Everybody learns the names and colors of the others.
+        for index in 0..self.players.len() {
+            send_to(socket, stats, &COMMANDS::Accept(self.settings(index)), self.players[index].address);
+        }
+        for index in 0..self.spectators.len() {
+            send_to(socket, stats, &COMMANDS::Accept(self.spectator_settings(index)), self.spectators[index].address);
+        }
+        self.send_frame(socket, stats);
+        self.send_inputs(socket, stats);
+    }
+
+    /* Everybody gets the new frame as a delta to the last one they acknowledged, or in full
+     * every few ticks and whenever there is nothing to build on. */
+    fn send_frame(&mut self, socket: &dyn Transport, stats: &mut PacketStats) {
+        let frame = self.frame();
+        let keyframe = frame.tick % KEYFRAME_INTERVAL == 0;
+        for player in self.players.iter().filter(|player| !player.lockstep).chain(&self.spectators) {
+            let delta = player.acked
+                .filter(|_| !keyframe)
+                .and_then(|acked| self.history.get(acked))
+                .and_then(|baseline| diff(baseline, &frame));
+            let command = match delta {
+                Some(delta) => COMMANDS::ServerDelta(delta),
+                None => COMMANDS::ServerData(frame.clone()),
+            };
+            send_to(socket, stats, &command, player.address);
+        }
+        self.history.push(frame);
+    }
+
+    /* Lockstep clients get the directions of all the ticks they did not simulate yet. */
+    fn send_inputs(&mut self, socket: &dyn Transport, stats: &mut PacketStats) {
+        for player in self.players.iter_mut().filter(|player| player.lockstep) {
+            // Until the client has the first frame it does not know the match began.
+            if player.acked.is_none() {
+                if let Some(start) = self.history.get(0) {
+                    send_to(socket, stats, &COMMANDS::ServerData(start.clone()), player.address);
+                }
+            }
+            let first = player.acked.unwrap_or(0) + 1;
+            if self.inputs.front().is_some_and(|(oldest, _)| *oldest > first) {
+                log!(self.log, "room {}: {} fell behind, sending frames", self.id, player.name);
+                player.lockstep = false;
+                player.acked = None;
+                continue;
+            }
+            let directions: Vec<Vec<DIRECTION>> = self.inputs.iter()
+                .filter(|(tick, _)| *tick >= first)
+                .map(|(_, directions)| directions.clone())
+                .collect();
+            if !directions.is_empty() {
+                send_to(socket, stats, &COMMANDS::Inputs(InputBatch{first, directions}), player.address);
+            }
+        }
+    }
+
+    /* Settings and the whole room or board again, for a player who lost track of the match. */
+    fn welcome_back(&self, index: usize, socket: &dyn Transport, stats: &mut PacketStats) {
+        let address = self.players[index].address;
+        send_to(socket, stats, &COMMANDS::Accept(self.settings(index)), address);
+        match self.state {
+            // The inputs it missed come with the next step.
+            RoomState::Playing if self.players[index].lockstep => {},
+            RoomState::Playing => send_to(socket, stats, &COMMANDS::ServerData(self.frame()), address),
+            RoomState::Finished => {},
+            _ => send_to(socket, stats, &COMMANDS::RoomUpdate(self.status()), address),
+        }
+    }
+
+    /* The same for a spectator, who may come in at any moment of the match. */
+    fn welcome_spectator(&self, index: usize, socket: &dyn Transport, stats: &mut PacketStats) {
+        let address = self.spectators[index].address;
+        send_to(socket, stats, &COMMANDS::Accept(self.spectator_settings(index)), address);
+        match self.state {
+            RoomState::Playing => send_to(socket, stats, &COMMANDS::ServerData(self.frame()), address),
+            RoomState::Finished => {},
+            _ => send_to(socket, stats, &COMMANDS::RoomUpdate(self.status()), address),
+        }
+    }
+
+    /* Ends the match and tells every player about it, except the one who ended it. */
+    fn finish(&mut self, reason: &str, quitter: Option<usize>) {
+        self.state = RoomState::Finished;
+        log!(self.log, "room {}: {}", self.id, reason);
+        for (index, player) in self.players.iter_mut().enumerate() {
+            player.endgame = EndgameNotice::new(quitter != Some(index));
+        }
+        for spectator in &mut self.spectators {
+            spectator.endgame = EndgameNotice::new(true);
+        }
+    }
+
+    /* Passes a line on to everybody in the room, unless its sender is flooding it. */
+    fn chat(&mut self, index: usize, spectator: bool, text: &str, socket: &dyn Transport, stats: &mut PacketStats) {
+        let sender = match spectator {
+            true => &mut self.spectators[index],
+            false => &mut self.players[index],
+        };
+        let text = sanitize_chat(text);
+        if text.trim().is_empty() {
+            return;
+        }
+        if !sender.chat_limit.allow() {
+            let notice = ChatMessage{from: String::new(), text: String::from("slow down, too many messages")};
+            send_to(socket, stats, &COMMANDS::Chat(notice), sender.address);
+            return;
+        }
+        let message = ChatMessage{from: sender.name.clone(), text};
+        log!(self.log, "room {}: <{}> {}", self.id, message.from, message.text);
+        self.broadcast(&COMMANDS::Chat(message), socket, stats);
+    }
+
+    /* Spectators can only come and go, and chat. */
+    fn handle_spectator(&mut self, index: usize, command: COMMANDS, socket: &dyn Transport, stats: &mut PacketStats) {
+        self.spectators[index].heartbeat.heard();
+
+        match command {
+            COMMANDS::Connect{..} | COMMANDS::WatchRoom(_) | COMMANDS::Reconnect{..}
+                if self.state != RoomState::Finished => self.welcome_spectator(index, socket, stats),
+            COMMANDS::Endgame => {
+                send_to(socket, stats, &COMMANDS::EndgameAck, self.spectators[index].address);
+                let spectator = self.spectators.remove(index);
+                log!(self.log, "room {}: {} stopped watching", self.id, spectator.name);
+            },
+            COMMANDS::EndgameAck => self.spectators[index].endgame.acknowledge(),
+            COMMANDS::FrameAck(tick) => {
+                let spectator = &mut self.spectators[index];
+                spectator.acked = spectator.acked.max(Some(tick));
+            },
+            COMMANDS::Chat(message) => self.chat(index, true, &message.text, socket, stats),
+            _ => {},
+        }
+    }
+
+    /* Changes made by players while waiting for the match to begin. */
+    fn handle_waiting(&mut self, index: usize, command: COMMANDS) -> bool {
+        match command {
+            COMMANDS::Ready(ready) => {
+                self.players[index].ready = ready;
+                // Changing your mind stops the countdown.
+                if !ready {
+                    self.state = RoomState::Waiting;
+                }
+            },
+            COMMANDS::Color(color) if self.state == RoomState::Waiting && color < PLAYER_COLORS => {
+                if self.players.iter().all(|player| player.color != color) {
+                    self.players[index].color = color;
+                }
+            },
+            COMMANDS::StartMatch if index == 0 && self.state == RoomState::Waiting => {
+                let full = self.players.len() == PLAYERS_PER_ROOM;
+                if !full || self.players.iter().any(|player| !player.ready) {
+                    return false;
+                }
+                log!(self.log, "room {}: counting down", self.id);
+                self.state = RoomState::Countdown(COUNTDOWN);
+                self.last_step = Instant::now();
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    fn handle(&mut self, index: usize, command: COMMANDS, socket: &dyn Transport, stats: &mut PacketStats) {
+        self.players[index].heartbeat.heard();
+
+        match command {
+            COMMANDS::Key{seq, key} => {
+                let player = &mut self.players[index];
+                let snake = &self.snakes[index];
+                if !player.input_filter.accept(seq, stats) {
+                    return;
+                }
+                match DIRECTIONS.get(&key) {
+                    // No key was pressed during the client's tick.
+                    None if key == -1 => player.taken = seq,
+                    None => player.strike("sent an unknown key", self.log),
+                    // One turn per tick, and never straight back into the own body.
+                    Some(direction) if !player.turned && snake.can_turn(direction) => {
+                        player.direction = direction.clone();
+                        player.turned = true;
+                        player.taken = seq;
+                    },
+                    // Pressing two keys within a tick happens, so this is no strike, but it is no turn either.
+                    Some(_) => log!(self.log, "room {}: {} turned again or back, input {} ignored", self.id, player.name, seq),
+                }
+            },
+            // The answer to joining got lost, the client is still asking.
+            COMMANDS::Connect{..} | COMMANDS::CreateRoom(_) | COMMANDS::JoinRoom(_)
+                if self.state != RoomState::Finished => self.welcome_back(index, socket, stats),
+            COMMANDS::Reconnect{..} if self.state != RoomState::Finished => self.welcome_back(index, socket, stats),
+            COMMANDS::Endgame => {
+                send_to(socket, stats, &COMMANDS::EndgameAck, self.players[index].address);
+                match self.state {
+                    RoomState::Playing => {
+                        let reason = format!("{} quit", self.players[index].name);
+                        self.finish(&reason, Some(index));
+                    },
+                    RoomState::Finished => {},
+                    _ => self.leave(index, "left", socket, stats),
+                }
+            },
+            COMMANDS::EndgameAck => self.players[index].endgame.acknowledge(),
+            COMMANDS::FrameAck(tick) if tick > self.game.get_tick() => {
+                self.players[index].strike("acknowledged a tick from the future", self.log);
+            },
+            COMMANDS::FrameAck(tick) => {
+                let player = &mut self.players[index];
+                player.acked = player.acked.max(Some(tick));
+            },
+            COMMANDS::Checksum{tick, checksum} => {
+                let expected = self.checksums.iter().find(|(known, _)| *known == tick).map(|(_, known)| *known);
+                let player = &mut self.players[index];
+                if player.lockstep && expected.is_some_and(|expected| expected != checksum) {
+                    // The server's game is the one that counts, frames take over from the client's.
+                    log!(self.log, "room {}: {} out of sync at tick {}, sending frames", self.id, player.name, tick);
+                    player.lockstep = false;
+                    player.acked = None;
+                }
+            },
+            command @ COMMANDS::Ready(_) | command @ COMMANDS::Color(_) | command @ COMMANDS::StartMatch => {
+                let waiting = matches!(self.state, RoomState::Waiting | RoomState::Countdown(_));
+                if waiting && self.handle_waiting(index, command) {
+                    self.broadcast(&COMMANDS::RoomUpdate(self.status()), socket, stats);
+                }
+            },
+            COMMANDS::Chat(message) => self.chat(index, false, &message.text, socket, stats),
+            _ => {},
+        }
+    }
+
+    /* One simulation step, the same as a single player game does locally. */
+    fn step(&mut self, socket: &dyn Transport, stats: &mut PacketStats) {
+        let directions: Vec<DIRECTION> = self.players.iter().map(|player| player.direction.clone()).collect();
+        self.game.step(&mut self.snakes, &directions);
+        for player in &mut self.players {
+            player.turned = false;
+            player.applied = player.taken;
+        }
+
+        if self.seed.is_some() {
+            // The directions the snakes really took, a turn back is already left out.
+            let tick = self.game.get_tick();
+            self.inputs.push_back((tick, self.snakes.iter().map(|snake| snake.direction.clone()).collect()));
+            if self.inputs.len() > INPUT_HISTORY {
+                self.inputs.pop_front();
+            }
+            if tick % CHECKSUM_INTERVAL == 0 {
+                self.checksums.push_back((tick, self.game.checksum(&self.snakes)));
+                if self.checksums.len() > CHECKSUM_HISTORY {
+                    self.checksums.pop_front();
+                }
+            }
+        }
+        self.send_frame(socket, stats);
+        self.send_inputs(socket, stats);
+
+        if self.game.is_board_full() {
+            self.finish("board full", None);
+        }
+        else if self.game.check_collisions(&self.snakes) {
+            self.finish("game over", None);
+        }
+    }
+
+    fn update(&mut self, config: &ServerConfig, socket: &dyn Transport, stats: &mut PacketStats) {
+        for player in self.players.iter_mut().chain(&mut self.spectators) {
+            if player.heartbeat.due() {
+                send_to(socket, stats, &COMMANDS::Heartbeat, player.address);
+            }
+        }
+        if self.state != RoomState::Finished {
+            let (id, log) = (self.id, self.log);
+            self.spectators.retain(|spectator| {
+                let present = !spectator.heartbeat.timed_out();
+                if !present {
+                    log!(log, "room {}: {} stopped watching", id, spectator.name);
+                }
+                present
+            });
+        }
+
+        match self.state {
+            RoomState::Waiting | RoomState::Countdown(_) => {
+                if let Some(index) = self.players.iter().position(|player| player.heartbeat.timed_out()) {
+                    self.leave(index, "timed out", socket, stats);
+                }
+            },
+            _ => {},
+        }
+
+        match self.state {
+            RoomState::Waiting => {},
+            RoomState::Countdown(remaining) => {
+                if self.last_step.elapsed() >= Duration::from_secs(1) {
+                    self.last_step = Instant::now();
+                    if remaining > 1 {
+                        self.state = RoomState::Countdown(remaining - 1);
+                        self.broadcast(&COMMANDS::RoomUpdate(self.status()), socket, stats);
+                    }
+                    else {
+                        self.start(socket, stats);
+                    }
+                }
+            },
+            RoomState::Playing => {
+                /* A silent player gets a grace period to come back, the match waits meanwhile. */
+                let missing = self.players.iter().find(|player| player.heartbeat.timed_out());
+                match (missing, self.waiting_since) {
+                    (Some(player), None) => {
+                        log!(self.log, "room {}: waiting for {}", self.id, player.name);
+                        self.waiting_since = Some(Instant::now());
+                    },
+                    (None, Some(_)) => {
+                        self.waiting_since = None;
+                        self.last_step = Instant::now();
+                    },
+                    (Some(player), Some(since)) if since.elapsed() >= config.grace_period => {
+                        let reason = format!("{} disconnected", player.name);
+                        self.finish(&reason, None);
+                    },
+                    _ => {},
+                }
+
+                let step = Duration::from_millis(self.game.get_refresh_timeout() as u64);
+                if self.waiting_since.is_none() && self.last_step.elapsed() >= step {
+                    self.last_step = Instant::now();
+                    self.step(socket, stats);
+                }
+            },
+            RoomState::Finished => {
+                let tick = self.game.get_tick();
+                let mut catch_up = false;
+                for player in self.players.iter_mut().chain(&mut self.spectators) {
+                    if player.endgame.due(&player.heartbeat) {
+                        // A lockstep client first needs the last inputs to end where the server did.
+                        match player.lockstep && player.acked < Some(tick) {
+                            true => catch_up = true,
+                            false => send_to(socket, stats, &COMMANDS::Endgame, player.address),
+                        }
+                    }
+                }
+                if catch_up {
+                    self.send_inputs(socket, stats);
+                }
+            },
+        }
+    }
+
+    /* Nothing is left to do once everybody is gone or knows the match is over. */
+    fn is_closed(&self) -> bool {
+        match self.state {
+            RoomState::Finished => self.players.iter().chain(&self.spectators).all(|player| !player.endgame.is_pending()),
+            _ => self.players.is_empty(),
+        }
+    }
+}
+
+fn send_to(socket: &dyn Transport, stats: &mut PacketStats, command: &COMMANDS, address: SocketAddr) {
+    for datagram in encode_datagrams(command) {
+        if socket.send_to(&datagram, address).is_err() {
+            stats.io_errors += 1;
+        }
+    }
+}
+
+/* Headless server hosting any number of two player matches.
+ *
+ * Players are told apart by their address, and by their session when they
+ * come back from another one. New players wait in the lobby, where they see
+ * the rooms, until they create one or join one. */
+pub struct Server {
+    socket: Box<dyn Transport>,
+    /* The same socket as the UDP transport, servers on other transports are not announced. */
+    announcer: Option<UdpSocket>,
+    config: ServerConfig,
+    lobby: Vec<Player>,
+    lobby_refresh: Interval,
+    announce: Interval,
+    rooms: Vec<Room>,
+    next_room: u32,
+    pub stats: PacketStats,
+}
+
+impl Server {
+    pub fn bind(address: SocketAddr, config: ServerConfig) -> io::Result<Self> {
+        // The "find games" screen joins a server at the address its announcement came from.
+        let (socket, announcer) = match config.transport {
+            TransportKind::Udp => {
+                let socket = UdpSocket::bind(address)?;
+                socket.set_nonblocking(true)?;
+                // Only IPv4 has broadcast, an IPv6 server is simply not announced.
+                let _ = socket.set_broadcast(true);
+                (Box::new(socket.try_clone()?) as Box<dyn Transport>, Some(socket))
+            },
+            transport => (transport.listen(address)?, None),
+        };
+        let socket = match &config.password {
+            Some(password) => Box::new(Authenticated::server(socket, Key::from_password(password))),
+            None => socket,
+        };
+        Ok(Server {
+            socket,
+            announcer,
+            config,
+            lobby: vec![],
+            lobby_refresh: Interval::new(LOBBY_REFRESH),
+            announce: Interval::new(ANNOUNCE_INTERVAL),
+            rooms: vec![],
+            next_room: 1,
+            stats: PacketStats::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+
+    /* Address a client on the same machine reaches the server at. */
+    pub fn loopback_addr(&self) -> SocketAddr {
+        let address = self.local_addr();
+        match address.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), address.port()),
+            IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), address.port()),
+            _ => address,
+        }
+    }
+
+    pub fn run(mut self) {
+        while !shutdown::requested() {
+            self.poll();
+            sleep(Duration::from_millis(1));
+        }
+        self.shut_down();
+    }
+
+    /* Everybody still here is told, so nobody waits for the server to time out. */
+    fn shut_down(&mut self) {
+        log!(self.config.log, "shutting down");
+        let addresses: Vec<SocketAddr> = self.lobby.iter()
+            .chain(self.rooms.iter().flat_map(|room| room.players.iter().chain(&room.spectators)))
+            .map(|player| player.address)
+            .collect();
+        for address in addresses {
+            send_to(&*self.socket, &mut self.stats, &COMMANDS::Reject(String::from("the server is shutting down")), address);
+            send_to(&*self.socket, &mut self.stats, &COMMANDS::Endgame, address);
+        }
+    }
+
+    /* Handles everything received so far and moves every room on. */
+    pub fn poll(&mut self) {
+        let mut buffer = [0; 10000];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((length, source)) => self.handle(&buffer[..length], source),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // A previous datagram could not be delivered, there may be more waiting.
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => self.stats.io_errors += 1,
+                Err(_) => {
+                    self.stats.io_errors += 1;
+                    break;
+                },
+            }
+        }
+
+        if self.announce.ready() {
+            if let Some(announcer) = &self.announcer {
+                announce(announcer, self.info());
+            }
+        }
+        self.update_lobby();
+        for room in &mut self.rooms {
+            room.update(&self.config, &*self.socket, &mut self.stats);
+        }
+        self.rooms.retain(|room| !room.is_closed());
+    }
+
+    fn update_lobby(&mut self) {
+        let timed_out = |player: &Player| player.heartbeat.timed_out();
+        for player in self.lobby.iter().filter(|player| timed_out(player)) {
+            log!(self.config.log, "lobby: {} timed out", player.name);
+        }
+        self.lobby.retain(|player| !timed_out(player));
+
+        let refresh = self.lobby_refresh.ready();
+        let rooms = self.room_list();
+        for player in &mut self.lobby {
+            if refresh {
+                send_to(&*self.socket, &mut self.stats, &COMMANDS::RoomList(rooms.clone()), player.address);
+            }
+            if player.heartbeat.due() {
+                send_to(&*self.socket, &mut self.stats, &COMMANDS::Heartbeat, player.address);
+            }
+        }
+    }
+
+    fn info(&self) -> ServerInfo {
+        let players = self.lobby.len() + self.rooms.iter().map(|room| room.players.len()).sum::<usize>();
+        ServerInfo {
+            name: self.config.name.clone(),
+            game_area_height: self.config.game_area_height,
+            game_area_width: self.config.game_area_width,
+            players: players as u32,
+            rooms: self.room_list().len() as u32,
+        }
+    }
+
+    fn room_list(&self) -> Vec<RoomInfo> {
+        self.rooms.iter()
+            .filter(|room| room.state != RoomState::Finished)
+            .map(|room| room.info())
+            .collect()
+    }
+
+    /* Settings for a player still choosing a room. */
+    fn lobby_settings(&self, player: &Player) -> GameSettings {
+        GameSettings {
+            game_area_height: self.config.game_area_height,
+            game_area_width: self.config.game_area_width,
+            food_count: self.config.food_count.unwrap_or(PLAYERS_PER_ROOM + 1) as u32,
+            player_names: vec![player.name.clone()],
+            session_token: player.session_token,
+            player: 0,
+            spectator: false,
+            player_colors: vec![player.color],
+            room: None,
+            lockstep: None,
+        }
+    }
+
+    fn find_player(&self, address: SocketAddr) -> Option<(usize, usize)> {
+        self.rooms.iter().enumerate().find_map(|(room_index, room)| {
+            room.players.iter()
+                .position(|player| player.address == address)
+                .map(|player_index| (room_index, player_index))
+        })
+    }
+
+    fn find_spectator(&self, address: SocketAddr) -> Option<(usize, usize)> {
+        self.rooms.iter().enumerate().find_map(|(room_index, room)| {
+            room.spectators.iter()
+                .position(|spectator| spectator.address == address)
+                .map(|spectator_index| (room_index, spectator_index))
+        })
+    }
+
+    fn handle(&mut self, packet: &[u8], source: SocketAddr) {
+        let in_room = self.find_player(source);
+        let watching = self.find_spectator(source);
+        let in_lobby = self.lobby.iter().position(|player| player.address == source);
+        let known = in_room.is_some() || watching.is_some() || in_lobby.is_some();
+
+        let command = match decode(packet) {
+            Ok(command) => command,
+            Err(error @ ProtocolError::VersionMismatch{..}) if !known => {
+                // Someone is trying to join with another version, tell them why it fails.
+                self.stats.malformed += 1;
+                send_to(&*self.socket, &mut self.stats, &COMMANDS::Reject(error.to_string()), source);
+                return;
+            },
+            Err(_) => {
+                self.stats.malformed += 1;
+                let log = self.config.log;
+                if let Some(player) = self.player_mut(source) {
+                    player.strike("sent a malformed packet", log);
+                }
+                self.kick_if_struck_out(source);
+                return;
+            },
+        };
+        if known {
+            self.stats.received += 1;
+        }
+        let log = self.config.log;
+        if let Some(player) = self.player_mut(source) {
+            if !player.flood_limit.allow() {
+                player.strike("is flooding the server", log);
+                self.kick_if_struck_out(source);
+                return;
+            }
+        }
+        // Anybody looking for games may ask how far away we are, those connected keep asking.
+        match command {
+            COMMANDS::Probe(sent) => {
+                send_to(&*self.socket, &mut self.stats, &COMMANDS::ProbeReply(sent), source);
+                return;
+            },
+            COMMANDS::Ping(seq) if known => {
+                send_to(&*self.socket, &mut self.stats, &COMMANDS::Pong(seq), source);
+                return;
+            },
+            _ => {},
+        }
+
+        if let Some((room, player)) = in_room {
+            self.rooms[room].handle(player, command, &*self.socket, &mut self.stats);
+        }
+        else if let Some((room, spectator)) = watching {
+            self.rooms[room].handle_spectator(spectator, command, &*self.socket, &mut self.stats);
+        }
+        else {
+            match (in_lobby, command) {
+                (Some(player), command) => self.handle_lobby(player, command),
+                (None, command @ COMMANDS::Connect{..}) => self.enter_lobby(&command, source),
+                (None, command @ COMMANDS::Reconnect{..}) => self.rejoin(&command, source),
+                (None, _) => self.stats.unknown_peer += 1,
+            }
+        }
+        self.kick_if_struck_out(source);
+    }
+
+    /* Whoever is at the address, in the lobby, playing or watching. */
+    fn player_mut(&mut self, address: SocketAddr) -> Option<&mut Player> {
+        self.lobby.iter_mut()
+            .chain(self.rooms.iter_mut().flat_map(|room| room.players.iter_mut().chain(room.spectators.iter_mut())))
+            .find(|player| player.address == address)
+    }
+
+    /* A client that keeps flooding or sending nonsense is sent away, the same as if it quit. */
+    fn kick_if_struck_out(&mut self, address: SocketAddr) {
+        let name = match self.player_mut(address) {
+            // Starting over, so what it keeps sending while leaving doesn't kick it again and again.
+            Some(player) if player.strikes >= MAX_STRIKES => {
+                player.strikes = 0;
+                player.name.clone()
+            },
+            _ => return,
+        };
+        log!(self.config.log, "{} at {} kicked", name, address);
+        let reason = String::from("kicked for sending too many or invalid messages");
+        send_to(&*self.socket, &mut self.stats, &COMMANDS::Reject(reason), address);
+
+        if let Some((room, index)) = self.find_player(address) {
+            let room = &mut self.rooms[room];
+            match room.state {
+                RoomState::Playing => room.finish(&format!("{} kicked", name), Some(index)),
+                _ => room.handle(index, COMMANDS::Endgame, &*self.socket, &mut self.stats),
+            }
+        }
+        else if let Some((room, index)) = self.find_spectator(address) {
+            self.rooms[room].handle_spectator(index, COMMANDS::Endgame, &*self.socket, &mut self.stats);
+        }
+        else if let Some(index) = self.lobby.iter().position(|player| player.address == address) {
+            self.handle_lobby(index, COMMANDS::Endgame);
+        }
+    }
+
+    fn enter_lobby(&mut self, request: &COMMANDS, source: SocketAddr) {
+        let name = match validate_connect(request) {
+            Ok(name) => name,
+            Err(reason) => {
+                send_to(&*self.socket, &mut self.stats, &COMMANDS::Reject(reason), source);
+                return;
+            },
+        };
+
+        log!(self.config.log, "lobby: {} connected from {}", name, source);
+        let player = Player::new(source, name, self.config.peer_timeout);
+        let settings = self.lobby_settings(&player);
+        let rooms = self.room_list();
+        send_to(&*self.socket, &mut self.stats, &COMMANDS::Accept(settings), source);
+        send_to(&*self.socket, &mut self.stats, &COMMANDS::RoomList(rooms), source);
+        self.lobby.push(player);
+    }
+
+    fn handle_lobby(&mut self, index: usize, command: COMMANDS) {
+        self.lobby[index].heartbeat.heard();
+        let address = self.lobby[index].address;
+
+        match command {
+            // The answer to the handshake got lost, the client is still asking.
+            COMMANDS::Connect{..} => {
+                let settings = self.lobby_settings(&self.lobby[index]);
+                send_to(&*self.socket, &mut self.stats, &COMMANDS::Accept(settings), address);
+            },
+            COMMANDS::CreateRoom(name) => {
+                let player = self.lobby.remove(index);
+                let mut name = sanitize_name(&name);
+                if name.trim().is_empty() {
+                    name = format!("Room {}", self.next_room);
+                }
+                log!(self.config.log, "room {}: {} created by {}", self.next_room, name, player.name);
+
+                let mut room = Room::new(self.next_room, name, &self.config);
+                self.next_room += 1;
+                room.add(player, &*self.socket, &mut self.stats);
+                self.rooms.push(room);
+            },
+            COMMANDS::JoinRoom(id) => {
+                let room = self.rooms.iter_mut().find(|room| room.id == id);
+                let reason = match room {
+                    Some(room) if room.is_open() => {
+                        let player = self.lobby.remove(index);
+                        room.add(player, &*self.socket, &mut self.stats);
+                        return;
+                    },
+                    Some(room) if room.state == RoomState::Waiting => "room is full",
+                    Some(_) => "match already started",
+                    None => "no such room",
+                };
+                send_to(&*self.socket, &mut self.stats, &COMMANDS::Reject(String::from(reason)), address);
+            },
+            COMMANDS::WatchRoom(id) => {
+                match self.rooms.iter_mut().find(|room| room.id == id && room.state != RoomState::Finished) {
+                    Some(room) => {
+                        let spectator = self.lobby.remove(index);
+                        room.add_spectator(spectator, &*self.socket, &mut self.stats);
+                    },
+                    None => {
+                        let reject = COMMANDS::Reject(String::from("no such room"));
+                        send_to(&*self.socket, &mut self.stats, &reject, address);
+                    },
+                }
+            },
+            COMMANDS::Endgame => {
+                send_to(&*self.socket, &mut self.stats, &COMMANDS::EndgameAck, address);
+                log!(self.config.log, "lobby: {} left", self.lobby[index].name);
+                self.lobby.remove(index);
+            },
+            _ => {},
+        }
+    }
+
+    /* A player takes over its place again, possibly from another address. */
+    fn rejoin(&mut self, request: &COMMANDS, source: SocketAddr) {
+        let token = match validate_reconnect(request) {
+            Ok(token) => token,
+            Err(reason) => {
+                send_to(&*self.socket, &mut self.stats, &COMMANDS::Reject(reason), source);
+                return;
+            },
+        };
+        let found = self.rooms.iter().enumerate()
+            .filter(|(_, room)| room.state != RoomState::Finished)
+            .find_map(|(room_index, room)| {
+                room.players.iter()
+                    .position(|player| player.session_token == token)
+                    .map(|player_index| (room_index, player_index))
+            });
+
+        let watching = self.rooms.iter().enumerate()
+            .filter(|(_, room)| room.state != RoomState::Finished)
+            .find_map(|(room_index, room)| {
+                room.spectators.iter()
+                    .position(|spectator| spectator.session_token == token)
+                    .map(|spectator_index| (room_index, spectator_index))
+            });
+        if let Some((room, spectator)) = watching {
+            let room = &mut self.rooms[room];
+            let spectator_state = &mut room.spectators[spectator];
+            spectator_state.address = source;
+            spectator_state.heartbeat.heard();
+            spectator_state.acked = None;
+            room.welcome_spectator(spectator, &*self.socket, &mut self.stats);
+            return;
+        }
+
+        let (room, player) = match found {
+            Some(found) => found,
+            None => {
+                send_to(&*self.socket, &mut self.stats, &COMMANDS::Reject(String::from("unknown session")), source);
+                return;
+            },
+        };
+
+        let room = &mut self.rooms[room];
+        log!(room.log, "room {}: {} came back from {}", room.id, room.players[player].name, source);
+        let player_state = &mut room.players[player];
+        player_state.address = source;
+        player_state.heartbeat.heard();
+        // A restarted client numbers its inputs from the beginning again.
+        player_state.input_filter = SequenceFilter::default();
+        player_state.taken = 0;
+        player_state.applied = 0;
+        player_state.acked = None;
+        // A restarted client has no game of its own to go on with.
+        player_state.lockstep = false;
+        room.welcome_back(player, &*self.socket, &mut self.stats);
+    }
+}
@@ -0,0 +1,71 @@
+use std::panic;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+
+use ncurses::endwin;
+
+use crate::network::Farewell;
+
+/* Time a server running in this process gets to tell its clients, before the process exits. */
+static SERVER_NOTICE: Duration = Duration::from_millis(100);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static FAREWELL: Mutex<Option<Farewell>> = Mutex::new(None);
+
+/* Makes sure that however the program ends, the terminal is given back the way it was
+ * and the peer hears about it, instead of waiting for us to time out.
+ *
+ * A panic leaves ncurses, says goodbye to the peer and only then prints what went wrong,
+ * so it can be read. SIGINT and SIGTERM only ask the program to stop: ncurses can't be used
+ * from the signal handler's thread, so the loops of the game and the server do the rest. */
+pub fn install() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore();
+        default_hook(info);
+        exit(101);
+    }));
+    ctrlc::set_handler(|| REQUESTED.store(true, Ordering::SeqCst))
+        .expect("couldn't install the signal handler");
+}
+
+/* Called from every loop of the game, which is the thread drawing on the screen. */
+pub fn exit_if_requested() {
+    if requested() {
+        restore();
+        eprintln!("Interrupted, the game was ended.");
+        exit(130);
+    }
+}
+
+/* The peer to send Endgame to when the program stops on its own, `None` once there is nobody. */
+pub fn notify_on_exit(farewell: Option<Farewell>) {
+    if let Ok(mut current) = FAREWELL.lock() {
+        *current = farewell;
+    }
+}
+
+/* True once the program was asked to stop, servers check it to say goodbye to their clients. */
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+fn restore() {
+    // Does nothing when ncurses was never started or has been left already.
+    endwin();
+    // The lock is only held for a moment, unless it is held by the thread that panicked.
+    if let Ok(farewell) = FAREWELL.try_lock() {
+        if let Some(farewell) = farewell.as_ref() {
+            farewell.send();
+        }
+    }
+}
+
+fn exit(code: i32) -> ! {
+    REQUESTED.store(true, Ordering::SeqCst);
+    sleep(SERVER_NOTICE);
+    process::exit(code);
+}
@@ -0,0 +1,338 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::str::FromStr;
+use std::time::Duration;
+
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::handshake::{HandshakeError, MidHandshake};
+use tungstenite::{Message, WebSocket};
+
+use crate::protocol::packet_length;
+
+/* A client that does not read its messages is dropped once this much waits for it. */
+static MAX_BACKLOG: usize = 1 << 20;
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/* Carries the encoded packets between the server and its clients.
+ *
+ * Every transport looks like a datagram socket: a packet goes out to an address and
+ * comes in from one, and receiving never blocks but fails with `WouldBlock` instead.
+ * Stream transports keep a connection per address, the same packets are written to
+ * them one after the other and cut apart again by the length in their header. */
+pub trait Transport: Send {
+    fn send_to(&self, packet: &[u8], address: SocketAddr) -> io::Result<usize>;
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, packet: &[u8], address: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, packet, address)
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buffer)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TransportKind {
+    Udp,
+    Tcp,
+    WebSocket,
+}
+
+impl TransportKind {
+    /* The server's side, listening for clients. */
+    pub fn listen(&self, address: SocketAddr) -> io::Result<Box<dyn Transport>> {
+        Ok(match self {
+            TransportKind::Udp => {
+                let socket = UdpSocket::bind(address)?;
+                socket.set_nonblocking(true)?;
+                Box::new(socket)
+            },
+            TransportKind::Tcp => Box::new(StreamTransport::<Framed>::listen(address)?),
+            TransportKind::WebSocket => Box::new(StreamTransport::<WebSocketStream>::listen(address)?),
+        })
+    }
+
+    /* The client's side, `bind` only matters to UDP. Stream transports connect right away. */
+    pub fn connect(&self, bind: SocketAddr, server: SocketAddr) -> io::Result<Box<dyn Transport>> {
+        Ok(match self {
+            TransportKind::Udp => {
+                let socket = UdpSocket::bind(bind)?;
+                socket.set_nonblocking(true)?;
+                Box::new(socket)
+            },
+            TransportKind::Tcp => Box::new(StreamTransport::<Framed>::connect(server)?),
+            TransportKind::WebSocket => Box::new(StreamTransport::<WebSocketStream>::connect(server)?),
+        })
+    }
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "udp" => Ok(TransportKind::Udp),
+            "tcp" => Ok(TransportKind::Tcp),
+            "ws" => Ok(TransportKind::WebSocket),
+            _ => Err(format!("unknown transport {}, expected udp, tcp or ws", name)),
+        }
+    }
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportKind::Udp => write!(f, "udp"),
+            TransportKind::Tcp => write!(f, "tcp"),
+            TransportKind::WebSocket => write!(f, "ws"),
+        }
+    }
+}
+
+/* A connection carrying whole packets. */
+pub trait PacketStream: Sized + Send {
+    fn accept(stream: TcpStream) -> io::Result<Self>;
+    fn connect(stream: TcpStream, server: SocketAddr) -> io::Result<Self>;
+    fn send(&mut self, packet: &[u8]) -> io::Result<()>;
+    /* A whole packet once there is one, `WouldBlock` until then. */
+    fn receive(&mut self) -> io::Result<Vec<u8>>;
+}
+
+/* TCP with the packets cut apart by the length in their header, and what could not be
+ * written yet kept until the socket takes it. */
+pub struct Framed {
+    stream: TcpStream,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+}
+
+impl PacketStream for Framed {
+    fn accept(stream: TcpStream) -> io::Result<Self> {
+        Ok(Framed{stream, incoming: vec![], outgoing: vec![]})
+    }
+
+    fn connect(stream: TcpStream, _server: SocketAddr) -> io::Result<Self> {
+        Framed::accept(stream)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        if self.outgoing.len() + packet.len() > MAX_BACKLOG {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "peer is not reading"));
+        }
+        self.outgoing.extend_from_slice(packet);
+        self.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<Vec<u8>> {
+        self.flush()?;
+        loop {
+            let length = packet_length(&self.incoming)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))?;
+            if let Some(length) = length.filter(|length| *length <= self.incoming.len()) {
+                return Ok(self.incoming.drain(..length).collect());
+            }
+            let mut buffer = [0; 4096];
+            match self.stream.read(&mut buffer)? {
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")),
+                read => self.incoming.extend_from_slice(&buffer[..read]),
+            }
+        }
+    }
+}
+
+impl Framed {
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+                Ok(written) => {
+                    self.outgoing.drain(..written);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/* A WebSocket, every packet is a binary message of its own. Browsers can only talk this. */
+pub enum WebSocketStream {
+    Handshake(Option<Box<MidHandshake<ServerHandshake<TcpStream, NoCallback>>>>),
+    Open(Box<WebSocket<TcpStream>>),
+}
+
+fn websocket_error(error: tungstenite::Error) -> io::Error {
+    match error {
+        tungstenite::Error::Io(error) => error,
+        error => io::Error::new(io::ErrorKind::ConnectionAborted, error.to_string()),
+    }
+}
+
+impl PacketStream for WebSocketStream {
+    fn accept(stream: TcpStream) -> io::Result<Self> {
+        match tungstenite::accept(stream) {
+            Ok(websocket) => Ok(WebSocketStream::Open(Box::new(websocket))),
+            Err(HandshakeError::Interrupted(handshake)) => Ok(WebSocketStream::Handshake(Some(Box::new(handshake)))),
+            Err(HandshakeError::Failure(error)) => Err(websocket_error(error)),
+        }
+    }
+
+    /* The client does the handshake before going non-blocking. */
+    fn connect(stream: TcpStream, server: SocketAddr) -> io::Result<Self> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        let (websocket, _) = tungstenite::client(format!("ws://{}/", server), stream)
+            .map_err(|error| io::Error::new(io::ErrorKind::ConnectionRefused, error.to_string()))?;
+        websocket.get_ref().set_read_timeout(None)?;
+        websocket.get_ref().set_nonblocking(true)?;
+        Ok(WebSocketStream::Open(Box::new(websocket)))
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self {
+            WebSocketStream::Open(websocket) => match websocket.send(Message::Binary(packet.to_vec())) {
+                // The message waits in the socket's buffer.
+                Err(tungstenite::Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+                result => result.map_err(websocket_error),
+            },
+            WebSocketStream::Handshake(_) => Err(io::Error::new(io::ErrorKind::NotConnected, "handshake not done")),
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<Vec<u8>> {
+        if let WebSocketStream::Handshake(handshake) = self {
+            let pending = handshake.take().ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+            match pending.handshake() {
+                Ok(websocket) => *self = WebSocketStream::Open(Box::new(websocket)),
+                Err(HandshakeError::Interrupted(waiting)) => {
+                    *handshake = Some(Box::new(waiting));
+                    return Err(io::ErrorKind::WouldBlock.into());
+                },
+                Err(HandshakeError::Failure(error)) => return Err(websocket_error(error)),
+            }
+        }
+        if let WebSocketStream::Open(websocket) = self {
+            loop {
+                match websocket.read() {
+                    Ok(Message::Binary(packet)) => return Ok(packet),
+                    // Pings are answered by the socket itself, anything else is not for us.
+                    Ok(Message::Close(_)) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(_) => {},
+                    Err(tungstenite::Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                        // Whatever did not fit into the socket before goes out now.
+                        return match websocket.flush() {
+                            Err(tungstenite::Error::Io(ref e)) if e.kind() != io::ErrorKind::WouldBlock =>
+                                Err(io::Error::new(e.kind(), e.to_string())),
+                            _ => Err(io::ErrorKind::WouldBlock.into()),
+                        };
+                    },
+                    Err(error) => return Err(websocket_error(error)),
+                }
+            }
+        }
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+struct Peer<S> {
+    address: SocketAddr,
+    stream: S,
+}
+
+/* A connection per client on the server, the one to the server on a client. */
+pub struct StreamTransport<S> {
+    listener: Option<TcpListener>,
+    local: SocketAddr,
+    peers: RefCell<Vec<Peer<S>>>,
+}
+
+impl<S: PacketStream> StreamTransport<S> {
+    pub fn listen(address: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(StreamTransport {
+            local: listener.local_addr()?,
+            listener: Some(listener),
+            peers: RefCell::new(vec![]),
+        })
+    }
+
+    pub fn connect(server: SocketAddr) -> io::Result<Self> {
+        let stream = TcpStream::connect_timeout(&server, CONNECT_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        let local = stream.local_addr()?;
+        Ok(StreamTransport {
+            listener: None,
+            local,
+            peers: RefCell::new(vec![Peer{address: server, stream: S::connect(stream, server)?}]),
+        })
+    }
+
+    fn accept(&self) {
+        let listener = match &self.listener {
+            Some(listener) => listener,
+            None => return,
+        };
+        while let Ok((stream, address)) = listener.accept() {
+            let ready = stream.set_nodelay(true).and_then(|()| stream.set_nonblocking(true));
+            // A client failing its handshake simply is not there.
+            if let Ok(stream) = ready.and_then(|()| S::accept(stream)) {
+                self.peers.borrow_mut().push(Peer{address, stream});
+            }
+        }
+    }
+}
+
+impl<S: PacketStream> Transport for StreamTransport<S> {
+    fn send_to(&self, packet: &[u8], address: SocketAddr) -> io::Result<usize> {
+        let mut peers = self.peers.borrow_mut();
+        let position = peers.iter().position(|peer| peer.address == address)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        if let Err(error) = peers[position].stream.send(packet) {
+            peers.remove(position);
+            return Err(error);
+        }
+        Ok(packet.len())
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.accept();
+        let mut peers = self.peers.borrow_mut();
+        let mut index = 0;
+        while index < peers.len() {
+            match peers[index].stream.receive() {
+                Ok(packet) if packet.len() <= buffer.len() => {
+                    buffer[..packet.len()].copy_from_slice(&packet);
+                    // The others go first next time, a busy client cannot keep them waiting.
+                    let peer = peers.remove(index);
+                    let address = peer.address;
+                    peers.push(peer);
+                    return Ok((packet.len(), address));
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => index += 1,
+                // Too large to be ours, or the connection is gone: the client is dropped
+                // and has to come back like it would after losing a UDP connection.
+                _ => {
+                    peers.remove(index);
+                },
+            }
+        }
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+}
@@ -0,0 +1,577 @@
+use std::cell::Cell;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use rusty_snake::auth::{Authenticated, Key};
+use rusty_snake::delta::FrameHistory;
+use rusty_snake::game::{DIRECTION, FoodKind, Game, Snake, CHECKSUM_INTERVAL};
+use rusty_snake::network::{ConnectError, Connection, Interval, Latency, SequenceFilter, MAX_CHAT_LENGTH};
+use rusty_snake::protocol::{decode, encode, ChatMessage, GameSettings, UdpFrame, COMMANDS, PROTOCOL_VERSION};
+use rusty_snake::proxy::{Impairment, Proxy};
+use rusty_snake::server::{Server, ServerConfig};
+use rusty_snake::transport::{Transport, TransportKind};
+
+static HEIGHT: i32 = 20;
+static WIDTH: i32 = 60;
+
+fn draw_block(_y: &i32, _x: &i32, _color: &i16) {}
+fn draw_food(_y: &i32, _x: &i32, _kind: &FoodKind) {}
+fn clear_field(_y: &i32, _x: &i32) {}
+
+fn loopback(port: u16) -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)
+}
+
+fn bad_network() -> Impairment {
+    Impairment {
+        loss: 0.1,
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(20),
+        duplicate: 0.1,
+        reorder: 0.1,
+    }
+}
+
+fn config(lockstep: bool, transport: TransportKind) -> ServerConfig {
+    ServerConfig {
+        name: String::from("Test"),
+        game_area_height: HEIGHT,
+        game_area_width: WIDTH,
+        food_count: None,
+        peer_timeout: Duration::from_secs(5),
+        grace_period: Duration::from_secs(5),
+        log: false,
+        lockstep,
+        transport,
+        password: None,
+    }
+}
+
+fn server(lockstep: bool, transport: TransportKind) -> Server {
+    Server::bind(loopback(0), config(lockstep, transport)).unwrap()
+}
+
+/* A server on its own thread. Returns where clients connect to. */
+fn start(transport: TransportKind) -> SocketAddr {
+    let server = server(false, transport);
+    let address = server.local_addr();
+    thread::spawn(move || server.run());
+    address
+}
+
+/* Lets in only clients that know the password "secret". */
+fn private_server() -> Server {
+    let config = ServerConfig{password: Some(String::from("secret")), ..config(false, TransportKind::Udp)};
+    Server::bind(loopback(0), config).unwrap()
+}
+
+/* A client's socket that signs everything with a key made from `password`. */
+fn signed(socket: Box<dyn Transport>, password: &str) -> Box<dyn Transport> {
+    Box::new(Authenticated::client(socket, Key::from_password(password)))
+}
+
+/* The server and a proxy in front of it, both on their own thread. */
+fn start_behind_proxy(server: Server, impairment: Impairment) -> SocketAddr {
+    let mut proxy = Proxy::bind(loopback(0), server.local_addr(), impairment).unwrap();
+    proxy.seed(43);
+    let address = proxy.local_addr();
+    thread::spawn(move || server.run());
+    thread::spawn(move || proxy.run());
+    address
+}
+
+/* Keeps going until `done`, the test fails when it takes too long. */
+fn until(what: &str, mut done: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !done() {
+        assert!(started.elapsed() < Duration::from_secs(20), "timed out waiting for {}", what);
+        sleep(Duration::from_millis(5));
+    }
+}
+
+/* The part of the game client that talks to the server, without the screen. */
+struct Client {
+    connection: Connection,
+    settings: GameSettings,
+    retry: Interval,
+    frame_filter: SequenceFilter,
+    history: FrameHistory,
+    frames: Vec<UdpFrame>,
+    /* The client's own game when the match runs in lockstep. */
+    game: Option<(Game, Vec<Snake>)>,
+    checksums: Vec<(u64, u64)>,
+    chat: Vec<ChatMessage>,
+    latency: Latency,
+    rejected: Option<String>,
+    ended: bool,
+}
+
+impl Client {
+    fn join(transport: TransportKind, address: SocketAddr, name: &str) -> Self {
+        let socket = transport.connect(loopback(0), address).unwrap();
+        Client::connect(socket, address, name).unwrap_or_else(|error| panic!("{} couldn't join: {}", name, error))
+    }
+
+    fn connect(socket: Box<dyn Transport>, address: SocketAddr, name: &str) -> Result<Self, ConnectError> {
+        let mut connection = Connection::new(socket, Some(address), Duration::from_secs(5));
+        let settings = connection.join(&COMMANDS::Connect{version: PROTOCOL_VERSION, name: String::from(name)})?;
+        Ok(Client {
+            connection,
+            settings,
+            retry: Interval::new(100),
+            frame_filter: SequenceFilter::default(),
+            history: FrameHistory::default(),
+            frames: vec![],
+            game: None,
+            checksums: vec![],
+            chat: vec![],
+            latency: Latency::new(),
+            rejected: None,
+            ended: false,
+        })
+    }
+
+    /* Sent again every now and then, the network may lose it. */
+    fn request(&mut self, command: COMMANDS) {
+        if self.retry.ready() {
+            self.connection.send(&command);
+        }
+    }
+
+    fn poll(&mut self) {
+        let mut buffer = [0; 10000];
+        loop {
+            let command = match self.connection.receive(&mut buffer) {
+                Ok(Some((command, _))) => command,
+                Ok(None) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("receive failed: {}", e),
+            };
+            match command {
+                COMMANDS::Accept(settings) => self.settings = settings,
+                COMMANDS::ServerData(frame) => self.frame(Some(frame)),
+                COMMANDS::ServerDelta(delta) => {
+                    let frame = self.history.apply(delta);
+                    self.frame(frame);
+                },
+                COMMANDS::Inputs(batch) => {
+                    if let Some((game, snakes)) = self.game.as_mut() {
+                        for (tick, directions) in (batch.first..).zip(&batch.directions) {
+                            if tick == game.get_tick() + 1 {
+                                game.step(snakes, directions);
+                                if tick % CHECKSUM_INTERVAL == 0 {
+                                    let checksum = game.checksum(snakes);
+                                    self.connection.send(&COMMANDS::Checksum{tick, checksum});
+                                    self.checksums.push((tick, checksum));
+                                }
+                            }
+                        }
+                        self.connection.send(&COMMANDS::FrameAck(game.get_tick()));
+                    }
+                },
+                COMMANDS::Chat(message) => self.chat.push(message),
+                COMMANDS::Pong(seq) => self.latency.pong(seq),
+                COMMANDS::Reject(reason) => self.rejected = Some(reason),
+                COMMANDS::Endgame => {
+                    self.connection.send(&COMMANDS::EndgameAck);
+                    self.ended = true;
+                },
+                _ => {},
+            }
+        }
+        self.connection.keep_alive();
+        self.latency.ping(&mut self.connection);
+    }
+
+    fn frame(&mut self, frame: Option<UdpFrame>) {
+        let frame = match frame {
+            Some(frame) if self.frame_filter.accept(frame.tick, &mut self.connection.stats) => frame,
+            _ => return,
+        };
+        self.connection.send(&COMMANDS::FrameAck(frame.tick));
+        if let (0, Some(seed)) = (frame.tick, self.settings.lockstep) {
+            let mut game = Game::new(HEIGHT, WIDTH, draw_food, draw_block, clear_field);
+            let snakes = vec![
+                Snake::new(HEIGHT/2, 2, DIRECTION::Right, draw_block, 0),
+                Snake::new(HEIGHT/2, WIDTH - 3, DIRECTION::Left, draw_block, 0),
+            ];
+            game.set_food_count(self.settings.food_count as usize);
+            game.restart(&snakes, seed);
+            self.game = Some((game, snakes));
+        }
+        self.history.push(frame.clone());
+        self.frames.push(frame);
+    }
+
+    fn started(&self) -> bool {
+        !self.frames.is_empty()
+    }
+}
+
+/* Alice opens a room, Bob joins it, both get ready and Alice starts the match. */
+fn start_match(transport: TransportKind, address: SocketAddr) -> (Client, Client) {
+    let alice = Client::join(transport, address, "Alice");
+    let bob = Client::join(transport, address, "Bob");
+    meet(alice, bob)
+}
+
+fn meet(mut alice: Client, mut bob: Client) -> (Client, Client) {
+    until("the room to open", || {
+        alice.request(COMMANDS::CreateRoom(String::from("Test")));
+        alice.poll();
+        alice.settings.room.is_some()
+    });
+    let room = alice.settings.room;
+    until("Bob to get in", || {
+        bob.request(COMMANDS::JoinRoom(room.unwrap()));
+        bob.poll();
+        alice.poll();
+        bob.settings.room == room
+    });
+    until("the match to start", || {
+        if !alice.started() && alice.retry.ready() {
+            alice.connection.send(&COMMANDS::Ready(true));
+            alice.connection.send(&COMMANDS::StartMatch);
+        }
+        if !bob.started() {
+            bob.request(COMMANDS::Ready(true));
+        }
+        alice.poll();
+        bob.poll();
+        alice.started() && bob.started()
+    });
+    (alice, bob)
+}
+
+/* Nobody steers, the snakes run into each other in the middle of the board. */
+fn play_to_the_end(mut alice: Client, mut bob: Client) {
+    assert_eq!(alice.settings.player, 0);
+    assert_eq!(bob.settings.player, 1);
+    until("the match to end", || {
+        alice.poll();
+        bob.poll();
+        alice.ended && bob.ended
+    });
+    for client in &[&alice, &bob] {
+        assert!(client.frames.len() > 10, "only {} frames arrived", client.frames.len());
+        assert!(client.frames.windows(2).all(|pair| pair[0].tick < pair[1].tick));
+        assert!(client.frames.last().unwrap().tick > 20);
+    }
+}
+
+#[test]
+fn proxy_delays_loses_and_duplicates() {
+    let server = UdpSocket::bind(loopback(0)).unwrap();
+    server.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let client = UdpSocket::bind(loopback(0)).unwrap();
+    let latency = Duration::from_millis(50);
+
+    let mut proxy = Proxy::bind(loopback(0), server.local_addr().unwrap(), Impairment{latency, ..Impairment::default()})
+        .unwrap();
+    let sent = Instant::now();
+    client.send_to(b"late", proxy.local_addr()).unwrap();
+    let mut buffer = [0; 100];
+    until("the delayed datagram", || {
+        proxy.poll();
+        server.set_nonblocking(true).unwrap();
+        server.recv_from(&mut buffer).is_ok()
+    });
+    assert!(sent.elapsed() >= latency);
+
+    let impairment = Impairment{loss: 0.5, duplicate: 1.0, ..Impairment::default()};
+    let mut proxy = Proxy::bind(loopback(0), server.local_addr().unwrap(), impairment).unwrap();
+    proxy.seed(44);
+    for _ in 0..200 {
+        client.send_to(b"lossy", proxy.local_addr()).unwrap();
+    }
+    sleep(Duration::from_millis(50));
+    proxy.poll();
+    assert_eq!(proxy.stats.lost + proxy.stats.duplicated, 200);
+    assert!(proxy.stats.lost > 50 && proxy.stats.lost < 150);
+    assert_eq!(proxy.stats.forwarded, 2 * proxy.stats.duplicated);
+
+    let mut received = 0;
+    server.set_nonblocking(false).unwrap();
+    while server.recv_from(&mut buffer).is_ok() {
+        received += 1;
+    }
+    assert_eq!(received, proxy.stats.forwarded);
+}
+
+#[test]
+fn match_over_a_bad_network() {
+    let address = start_behind_proxy(server(false, TransportKind::Udp), bad_network());
+    let (alice, bob) = start_match(TransportKind::Udp, address);
+    play_to_the_end(alice, bob);
+}
+
+#[test]
+fn match_over_tcp() {
+    let address = start(TransportKind::Tcp);
+    let (alice, bob) = start_match(TransportKind::Tcp, address);
+    play_to_the_end(alice, bob);
+}
+
+#[test]
+fn match_over_websocket() {
+    let address = start(TransportKind::WebSocket);
+    let (alice, bob) = start_match(TransportKind::WebSocket, address);
+    play_to_the_end(alice, bob);
+}
+
+#[test]
+fn lockstep_clients_stay_in_sync_over_a_bad_network() {
+    let address = start_behind_proxy(server(true, TransportKind::Udp), bad_network());
+    let (mut alice, mut bob) = start_match(TransportKind::Udp, address);
+    assert!(alice.settings.lockstep.is_some());
+    assert_eq!(alice.settings.lockstep, bob.settings.lockstep);
+
+    let mut turned = false;
+    until("the match to end", || {
+        // Bob turns away once, so the snakes do not simply meet head on.
+        if !turned && bob.game.as_ref().is_some_and(|(game, _)| game.get_tick() >= 5) {
+            bob.connection.send(&COMMANDS::Key{seq: 1, key: ncurses::KEY_UP});
+            turned = true;
+        }
+        alice.poll();
+        bob.poll();
+        alice.ended && bob.ended
+    });
+
+    // Only the first frame came, the rest of the match both simulated themselves.
+    assert_eq!(alice.frames.len(), 1);
+    assert_eq!(bob.frames.len(), 1);
+    let (alice_game, alice_snakes) = alice.game.as_ref().unwrap();
+    let (bob_game, bob_snakes) = bob.game.as_ref().unwrap();
+    assert!(alice_game.get_tick() > CHECKSUM_INTERVAL);
+    assert_eq!(alice_game.get_tick(), bob_game.get_tick());
+    assert!(alice_game.check_collisions(alice_snakes) || alice_game.is_board_full());
+    assert_eq!(alice_game.checksum(alice_snakes), bob_game.checksum(bob_snakes));
+    assert_eq!(alice.checksums, bob.checksums);
+    assert!(alice_snakes[1].body().iter().zip(bob_snakes[1].body()).all(|(a, b)| a == b));
+}
+
+#[test]
+fn chat_reaches_the_room_until_it_gets_flooded() {
+    let address = start(TransportKind::Udp);
+    let (mut alice, mut bob) = start_match(TransportKind::Udp, address);
+
+    let long = "x".repeat(MAX_CHAT_LENGTH + 20);
+    for text in ["hello", "\u{1b}[2Jclear", &long, "4", "5", "6", "7"] {
+        // Whatever the client claims, the server says who it is from.
+        alice.connection.send(&COMMANDS::Chat(ChatMessage{from: String::from("Bob"), text: String::from(text)}));
+    }
+    until("the chat to arrive", || {
+        alice.poll();
+        bob.poll();
+        bob.chat.len() == 5 && alice.chat.len() == 7
+    });
+
+    assert!(bob.chat.iter().all(|message| message.from == "Alice"));
+    assert_eq!(bob.chat[0].text, "hello");
+    assert_eq!(bob.chat[1].text, "[2Jclear");
+    assert_eq!(bob.chat[2].text.len(), MAX_CHAT_LENGTH);
+    // The last two were too many, only Alice hears about it.
+    let notices: Vec<&ChatMessage> = alice.chat.iter().filter(|message| message.from.is_empty()).collect();
+    assert_eq!(notices.len(), 2);
+}
+
+#[test]
+fn pings_measure_the_round_trip() {
+    let latency = Duration::from_millis(30);
+    let address = start_behind_proxy(server(false, TransportKind::Udp), Impairment{latency, ..Impairment::default()});
+    let mut alice = Client::join(TransportKind::Udp, address, "Alice");
+    assert!(alice.latency.loss().is_none());
+
+    until("the pong", || {
+        alice.poll();
+        alice.latency.rtt.is_some()
+    });
+    // The proxy holds back both the ping and the pong.
+    assert!(alice.latency.rtt.unwrap() >= 2 * latency);
+    assert_eq!(alice.latency.loss(), Some(0.0));
+}
+
+#[test]
+fn private_server_keeps_strangers_out() {
+    let server = private_server();
+    let address = server.local_addr();
+    thread::spawn(move || server.run());
+    let socket = || TransportKind::Udp.connect(loopback(0), address).unwrap();
+
+    assert!(matches!(Client::connect(signed(socket(), "guess"), address, "Mallory"), Err(ConnectError::Timeout)));
+
+    // Unsigned, nothing gets as far as the server to answer it.
+    let mallory = UdpSocket::bind(loopback(0)).unwrap();
+    mallory.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    mallory.send_to(&encode(&COMMANDS::Connect{version: PROTOCOL_VERSION, name: String::from("Mallory")}), address).unwrap();
+    assert!(mallory.recv_from(&mut [0; 2000]).is_err());
+
+    let alice = Client::connect(signed(socket(), "secret"), address, "Alice").unwrap_or_else(|error| panic!("{}", error));
+    let bob = Client::connect(signed(socket(), "secret"), address, "Bob").unwrap_or_else(|error| panic!("{}", error));
+    let (alice, bob) = meet(alice, bob);
+    play_to_the_end(alice, bob);
+}
+
+/* A client socket losing the first answer to a challenge it sends. */
+struct LosingAnswer {
+    socket: Box<dyn Transport>,
+    lost: Cell<bool>,
+}
+
+impl Transport for LosingAnswer {
+    fn send_to(&self, packet: &[u8], address: SocketAddr) -> io::Result<usize> {
+        let answer = match decode(packet) {
+            Ok(COMMANDS::Signed(signed)) => matches!(decode(&signed.packet), Ok(COMMANDS::Challenge(_))),
+            _ => false,
+        };
+        if answer && !self.lost.replace(true) {
+            return Ok(packet.len());
+        }
+        self.socket.send_to(packet, address)
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buffer)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+#[test]
+fn private_server_challenges_again_when_the_answer_got_lost() {
+    let server = private_server();
+    let address = server.local_addr();
+    thread::spawn(move || server.run());
+
+    let socket = LosingAnswer{socket: TransportKind::Udp.connect(loopback(0), address).unwrap(), lost: Cell::new(false)};
+    let alice = Client::connect(signed(Box::new(socket), "secret"), address, "Alice");
+    assert!(alice.is_ok(), "Alice couldn't join: {}", alice.err().unwrap());
+}
+
+#[test]
+fn private_match_over_a_bad_network() {
+    let address = start_behind_proxy(private_server(), bad_network());
+    let socket = || TransportKind::Udp.connect(loopback(0), address).unwrap();
+    let alice = Client::connect(signed(socket(), "secret"), address, "Alice").unwrap_or_else(|error| panic!("{}", error));
+    let bob = Client::connect(signed(socket(), "secret"), address, "Bob").unwrap_or_else(|error| panic!("{}", error));
+    let (alice, bob) = meet(alice, bob);
+    play_to_the_end(alice, bob);
+}
+
+#[test]
+fn private_server_challenges_only_requests_to_join() {
+    let server = private_server();
+    let address = server.local_addr();
+    thread::spawn(move || server.run());
+    let key = Key::from_password("secret");
+    let socket = UdpSocket::bind(loopback(0)).unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+
+    // A captured datagram of a running session, replayed from somewhere else.
+    socket.send_to(&key.sign(&encode(&COMMANDS::Heartbeat)), address).unwrap();
+    assert!(socket.recv_from(&mut [0; 2000]).is_err());
+
+    // However many requests come in, the address is challenged once a second at most.
+    let connect = key.sign(&encode(&COMMANDS::Connect{version: PROTOCOL_VERSION, name: String::from("Mallory")}));
+    for _ in 0..20 {
+        socket.send_to(&connect, address).unwrap();
+    }
+    assert!(socket.recv_from(&mut [0; 2000]).is_ok());
+    assert!(socket.recv_from(&mut [0; 2000]).is_err());
+}
+
+#[test]
+fn server_takes_one_turn_per_tick_and_never_back() {
+    let address = start(TransportKind::Udp);
+    let (mut alice, mut bob) = start_match(TransportKind::Udp, address);
+    let sent = alice.frames.last().unwrap().tick;
+
+    // Alice's snake heads right, so turning left would run it into itself.
+    alice.connection.send(&COMMANDS::Key{seq: 1, key: ncurses::KEY_LEFT});
+    // Of two turns during the same tick only the first one counts.
+    alice.connection.send(&COMMANDS::Key{seq: 2, key: ncurses::KEY_UP});
+    alice.connection.send(&COMMANDS::Key{seq: 3, key: ncurses::KEY_DOWN});
+    until("the match to end", || {
+        alice.poll();
+        bob.poll();
+        alice.ended && bob.ended
+    });
+
+    let heads: Vec<(i32, i32)> = alice.frames.iter()
+        .filter(|frame| frame.tick >= sent)
+        .map(|frame| (frame.snake1[0].y(), frame.snake1[0].x()))
+        .collect();
+    let moves: Vec<(i32, i32)> = heads.windows(2).map(|pair| (pair[1].0 - pair[0].0, pair[1].1 - pair[0].1)).collect();
+    assert!(moves.contains(&(-1, 0)), "the snake never went up: {:?}", moves);
+    assert!(moves.iter().all(|step| *step == (0, 1) || *step == (-1, 0)), "the snake went back or down: {:?}", moves);
+    // Only the turn up is acknowledged, the other two never counted.
+    let acknowledged: Vec<u64> = alice.frames.iter().map(|frame| frame.inputs[0]).collect();
+    assert!(acknowledged.contains(&2) && acknowledged.iter().all(|seq| *seq <= 2), "acknowledged {:?}", acknowledged);
+}
+
+#[test]
+fn flooding_clients_get_kicked() {
+    let address = start(TransportKind::Udp);
+    let mut mallory = Client::join(TransportKind::Udp, address, "Mallory");
+    for _ in 0..10 {
+        for _ in 0..50 {
+            mallory.connection.send(&COMMANDS::Heartbeat);
+        }
+        sleep(Duration::from_millis(1));
+    }
+    until("Mallory to be kicked", || {
+        mallory.poll();
+        mallory.rejected.is_some()
+    });
+}
+
+#[test]
+fn clients_sending_nonsense_get_kicked() {
+    let address = start(TransportKind::Udp);
+    let (mut alice, mut bob) = start_match(TransportKind::Udp, address);
+
+    // Slow enough not to count as a flood.
+    for seq in 1..=120 {
+        bob.connection.send(&COMMANDS::Key{seq, key: 12345});
+        sleep(Duration::from_millis(2));
+    }
+    until("Bob to be kicked", || {
+        alice.poll();
+        bob.poll();
+        bob.rejected.is_some() && alice.ended
+    });
+    assert!(!bob.ended);
+}
+
+#[test]
+fn only_known_sessions_can_be_taken_back() {
+    let address = start(TransportKind::Udp);
+    let (alice, _bob) = start_match(TransportKind::Udp, address);
+    let token = alice.settings.session_token;
+    let socket = UdpSocket::bind(loopback(0)).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let reply = |request: COMMANDS| {
+        socket.send_to(&encode(&request), address).unwrap();
+        let mut buffer = [0; 10000];
+        let (n, _) = socket.recv_from(&mut buffer).unwrap();
+        decode(&buffer[..n]).unwrap()
+    };
+
+    match reply(COMMANDS::Reconnect{version: PROTOCOL_VERSION, token: token.wrapping_add(1)}) {
+        COMMANDS::Reject(reason) => assert_eq!(reason, "unknown session"),
+        _ => panic!("a made up session was taken over"),
+    }
+    match reply(COMMANDS::Reconnect{version: PROTOCOL_VERSION + 1, token}) {
+        COMMANDS::Reject(reason) => assert!(reason.contains("protocol version"), "rejected for {}", reason),
+        _ => panic!("a client speaking another protocol took a session over"),
+    }
+    match reply(COMMANDS::Reconnect{version: PROTOCOL_VERSION, token}) {
+        COMMANDS::Accept(settings) => assert_eq!(settings.player, 0),
+        _ => panic!("Alice's session couldn't be taken back"),
+    }
+}